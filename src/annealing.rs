@@ -0,0 +1,192 @@
+use crate::op_finder::{alphabet, random_action, ProgramState};
+use crate::optimizer::{Budget, Meter, Optimized, Optimizer, Statistics};
+use crate::Program;
+use crate::VirtualMachine;
+use rand::prelude::*;
+use std::time::Instant;
+
+/// STOKE-style simulated annealing: a random walk over programs by single
+/// `Action`s, where a move that makes the program more expensive is still
+/// accepted with probability `exp(-increase / temperature)`. The cost of a
+/// program is its executed cost plus `mismatch_penalty` for every line of
+/// output that differs from the reference, so the walk may pass through
+/// incorrect programs on the way to a cheaper correct one. The temperature
+/// falls geometrically by `cooling` each iteration, and the budget's
+/// iterations are split over `restarts` walks from the input program.
+pub struct Annealing {
+    pub initial_temperature: f64,
+    pub cooling: f64,
+    pub restarts: usize,
+    pub mismatch_penalty: usize,
+    pub seed: u64,
+}
+
+impl Default for Annealing {
+    fn default() -> Self {
+        Self {
+            initial_temperature: 10.0,
+            cooling: 0.999,
+            restarts: 4,
+            mismatch_penalty: 100,
+            seed: 0,
+        }
+    }
+}
+
+impl Annealing {
+    /// Executed cost plus the correctness penalty. A program that fails to
+    /// run is charged as if none of its output matched.
+    fn cost(
+        &self,
+        vm: &mut VirtualMachine,
+        program: &Program,
+        reference: &(usize, Vec<String>),
+    ) -> usize {
+        let Ok((cost, output)) = vm.exe(program) else {
+            return reference.0 + self.mismatch_penalty * (reference.1.len() + 1);
+        };
+        let mismatches = reference
+            .1
+            .iter()
+            .zip(&output)
+            .filter(|(x, y)| x != y)
+            .count()
+            + reference.1.len().abs_diff(output.len());
+        cost + self.mismatch_penalty * mismatches
+    }
+}
+
+impl Optimizer for Annealing {
+    fn name(&self) -> &'static str {
+        "annealing"
+    }
+
+    fn optimize(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+    ) -> Optimized {
+        let start = Instant::now();
+        let total = budget.iterations;
+        let meter = Meter::new(budget);
+        let mut seen = (vm.executions(), vm.steps());
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let register_count = vm.register_count();
+        let alphabet = alphabet(program, register_count);
+
+        let mut best = program.clone();
+        let mut best_cost = vm.exe(program).map_or(reference.0, |(cost, _)| cost);
+        // Without a limit on iterations there is nothing to split, so each
+        // walk runs until it has cooled to 1% of the initial temperature
+        let bounded = total != usize::MAX;
+        let restarts = if bounded {
+            self.restarts.max(1)
+        } else {
+            usize::MAX
+        };
+        let cold = if self.cooling < 1.0 {
+            (0.01f64.ln() / self.cooling.ln()).ceil().max(1.0) as usize
+        } else {
+            usize::MAX
+        };
+        let mut iterations = 0;
+        for restart in 0..restarts {
+            if meter.stopped() {
+                break;
+            }
+            let length = if bounded {
+                total / restarts + usize::from(restart < total % restarts)
+            } else {
+                cold
+            };
+            let mut current = ProgramState::new(program.clone());
+            let mut current_cost = self.cost(vm, current.program(), reference);
+            let mut temperature = self.initial_temperature;
+            for _ in 0..length {
+                meter.charge((vm.executions(), vm.steps()), &mut seen);
+                if meter.stopped() {
+                    break;
+                }
+                iterations += 1;
+                let action = random_action(&current, &alphabet, register_count, &mut rng);
+                let candidate = current.applying(&vec![action]);
+                let cost = self.cost(vm, candidate.program(), reference);
+                let accept = cost <= current_cost
+                    || rng.gen::<f64>() < (-((cost - current_cost) as f64) / temperature).exp();
+                if accept {
+                    current = candidate;
+                    current_cost = cost;
+                    if current_cost < best_cost {
+                        let correct = vm
+                            .exe(current.program())
+                            .is_ok_and(|(_, output)| output == reference.1);
+                        if correct {
+                            best = current.program().clone();
+                            best_cost = current_cost;
+                        }
+                    }
+                }
+                temperature *= self.cooling;
+            }
+        }
+
+        Optimized {
+            program: best,
+            statistics: Statistics {
+                iterations,
+                original_cost: reference.0,
+                best_cost,
+                elapsed: start.elapsed(),
+                cache: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+
+    fn run(annealing: &Annealing) -> Optimized {
+        let program = count_to_x::prog(20);
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&program).unwrap();
+        annealing.optimize(&program, &mut vm, &reference, Budget::iterations(2_000))
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let annealing = Annealing {
+            seed: 7,
+            ..Annealing::default()
+        };
+        let (first, second) = (run(&annealing), run(&annealing));
+        assert_eq!(first.program.code(), second.program.code());
+        assert_eq!(first.statistics.best_cost, second.statistics.best_cost);
+
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&count_to_x::prog(20)).unwrap();
+        let (cost, output) = vm.exe(&first.program).unwrap();
+        assert_eq!(output, reference.1);
+        assert_eq!(cost, first.statistics.best_cost);
+    }
+
+    #[test]
+    fn finds_the_closed_form_of_count_to_x() {
+        // The README's example
+        let program = count_to_x::prog(100);
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&program).unwrap();
+        let optimized = Annealing::default().optimize(
+            &program,
+            &mut vm,
+            &reference,
+            Budget::iterations(10_000),
+        );
+        assert_eq!(optimized.program.code(), vec![set(0, 100), out(0)]);
+    }
+}
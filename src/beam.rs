@@ -0,0 +1,123 @@
+use crate::op_finder::ProgramState;
+use crate::optimizer::{Budget, Meter, Optimized, Optimizer, Statistics};
+use crate::Instruction;
+use crate::Program;
+use crate::VirtualMachine;
+use std::collections::HashSet;
+use std::time::Instant;
+
+/// Deterministic beam search over action chains. Every action
+/// `ProgramState::next_moves` offers is applied to every program in the
+/// frontier, and the `width` cheapest children with the reference output
+/// become the next frontier. The search stops once a step finds nothing
+/// cheaper than the best so far, or once the budget is spent, counting
+/// steps as iterations.
+pub struct Beam {
+    pub width: usize,
+}
+
+impl Default for Beam {
+    fn default() -> Self {
+        Self { width: 8 }
+    }
+}
+
+impl Optimizer for Beam {
+    fn name(&self) -> &'static str {
+        "beam"
+    }
+
+    fn optimize(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+    ) -> Optimized {
+        let start = Instant::now();
+        let meter = Meter::new(budget);
+        let mut spent = (vm.executions(), vm.steps());
+        let mut root = ProgramState::new(program.clone());
+        let mut best = program.clone();
+        let mut best_cost = match root.exe(vm) {
+            Ok(()) => root.executed_cost().unwrap(),
+            Err(_) => reference.0,
+        };
+
+        let mut frontier = vec![root];
+        let mut seen: HashSet<Vec<Instruction>> = HashSet::from([program.code()]);
+        let mut iterations = 0;
+        while !meter.exhausted(iterations) {
+            iterations += 1;
+            let mut children = vec![];
+            for state in &frontier {
+                for action in state.next_moves() {
+                    let mut child = state.applying(&vec![action]);
+                    if !seen.insert(child.program().code()) || child.exe(vm).is_err() {
+                        continue;
+                    }
+                    if child.is_correct(&reference.1) {
+                        children.push(child);
+                    }
+                }
+            }
+            // Stable, so ties keep the order the moves were generated in
+            children.sort_by_key(|x| x.executed_cost());
+            children.truncate(self.width.max(1));
+
+            match children.first() {
+                Some(cheapest) if cheapest.executed_cost().unwrap() < best_cost => {
+                    best = cheapest.program().clone();
+                    best_cost = cheapest.executed_cost().unwrap();
+                }
+                _ => break,
+            }
+            frontier = children;
+            meter.charge((vm.executions(), vm.steps()), &mut spent);
+        }
+
+        Optimized {
+            program: best,
+            statistics: Statistics {
+                iterations,
+                original_cost: reference.0,
+                best_cost,
+                elapsed: start.elapsed(),
+                cache: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::add_two;
+    use crate::testing::*;
+
+    #[test]
+    fn same_input_same_result() {
+        let program = add_two::prog();
+        let run = || {
+            let mut vm = VirtualMachine::new(4);
+            let reference = vm.exe(&program).unwrap();
+            Beam::default().optimize(&program, &mut vm, &reference, Budget::iterations(20))
+        };
+        let (first, second) = (run(), run());
+        assert_eq!(first.program.code(), second.program.code());
+        assert_eq!(first.statistics.best_cost, second.statistics.best_cost);
+        assert_eq!(first.statistics.iterations, second.statistics.iterations);
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples_without_costing_more() {
+        for (program, mut vm) in examples() {
+            let reference = vm.exe(&program).unwrap();
+            let optimized =
+                Beam::default().optimize(&program, &mut vm, &reference, Budget::iterations(20));
+            let (before, after) = assert_same_output_on(&mut vm, &program, &optimized.program);
+            assert!(after <= before, "{before} -> {after}");
+            assert_eq!(after, optimized.statistics.best_cost);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::Instruction;
+use crate::Program;
+use std::collections::BTreeSet;
+
+// Control flow graph over basic blocks. A block is a maximal run of
+// instructions that is only entered at its first instruction and only
+// left after its last one. Jumping to (or falling off) the end of the
+// program exits it.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+    pub exits: bool,
+}
+
+impl BasicBlock {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    blocks: Vec<BasicBlock>,
+    block_of: Vec<usize>,
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Self {
+        let len = program.len();
+        let mut leaders = vec![false; len];
+        if len > 0 {
+            leaders[0] = true;
+        }
+        for (idx, instruction) in program.iter().enumerate() {
+            if let Instruction::PCSetIfNotZero { jump_point, .. } = instruction.code() {
+                if jump_point < len {
+                    leaders[jump_point] = true;
+                }
+                if idx + 1 < len {
+                    leaders[idx + 1] = true;
+                }
+            }
+        }
+
+        let mut blocks = vec![];
+        let mut block_of = vec![0; len];
+        for idx in 0..len {
+            if leaders[idx] {
+                blocks.push(BasicBlock {
+                    start: idx,
+                    end: idx,
+                    successors: vec![],
+                    predecessors: vec![],
+                    exits: false,
+                });
+            }
+            let current = blocks.len() - 1;
+            blocks[current].end = idx + 1;
+            block_of[idx] = current;
+        }
+
+        for id in 0..blocks.len() {
+            let end = blocks[id].end;
+            let mut targets = vec![end];
+            if let Instruction::PCSetIfNotZero { jump_point, .. } =
+                program.get(end - 1).unwrap().code()
+            {
+                targets.push(jump_point);
+            }
+            for target in targets {
+                if target < len {
+                    let succ = block_of[target];
+                    if !blocks[id].successors.contains(&succ) {
+                        blocks[id].successors.push(succ);
+                        blocks[succ].predecessors.push(id);
+                    }
+                } else {
+                    blocks[id].exits = true;
+                }
+            }
+        }
+
+        Self { blocks, block_of }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    pub fn block(&self, id: usize) -> &BasicBlock {
+        &self.blocks[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Block containing the instruction at `index`.
+    pub fn block_of(&self, index: usize) -> usize {
+        self.block_of[index]
+    }
+
+    /// For every block, the blocks that dominate it (itself included).
+    /// Unreachable blocks are dominated by everything.
+    pub fn dominators(&self) -> Vec<BTreeSet<usize>> {
+        let all: BTreeSet<usize> = (0..self.blocks.len()).collect();
+        let mut dominators = vec![all; self.blocks.len()];
+        let order = self.reverse_postorder();
+        if let Some(&entry) = order.first() {
+            dominators[entry] = BTreeSet::from([entry]);
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in order.iter().skip(1) {
+                let mut dom: Option<BTreeSet<usize>> = None;
+                for pred in &self.blocks[block].predecessors {
+                    dom = Some(match dom {
+                        None => dominators[*pred].clone(),
+                        Some(d) => d.intersection(&dominators[*pred]).copied().collect(),
+                    });
+                }
+                let mut dom = dom.unwrap_or_default();
+                dom.insert(block);
+                if dom != dominators[block] {
+                    dominators[block] = dom;
+                    changed = true;
+                }
+            }
+        }
+        dominators
+    }
+
+    /// Blocks reachable from the entry, in reverse postorder.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = vec![];
+        if self.blocks.is_empty() {
+            return order;
+        }
+        let mut visited = vec![false; self.blocks.len()];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            if let Some(&succ) = self.blocks[block].successors.get(next) {
+                stack.push((block, next + 1));
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+        order.reverse();
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Counts r0 down from 3 and prints it.
+    fn countdown() -> Program {
+        Program::new(vec![set(0, 3), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)])
+    }
+
+    #[test]
+    fn splits_at_jumps_and_their_targets() {
+        let cfg = Cfg::new(&countdown());
+        let ranges: Vec<_> = cfg.blocks().iter().map(|x| x.range()).collect();
+        assert_eq!(ranges, vec![0..2, 2..4, 4..5]);
+        assert_eq!(cfg.block_of(3), 1);
+        assert_eq!(cfg.block(0).successors, vec![1]);
+        assert_eq!(cfg.block(1).successors, vec![2, 1]);
+        assert_eq!(cfg.block(1).predecessors, vec![0, 1]);
+        assert!(cfg.block(2).exits);
+        assert!(!cfg.block(1).exits);
+    }
+
+    #[test]
+    fn jumps_to_the_end_exit() {
+        let cfg = Cfg::new(&Program::new(vec![set(0, 1), jnz(0, 3), out(0)]));
+        assert_eq!(cfg.len(), 2);
+        assert!(cfg.block(0).exits);
+        assert_eq!(cfg.block(0).successors, vec![1]);
+    }
+
+    #[test]
+    fn empty_program_has_no_blocks() {
+        let cfg = Cfg::new(&Program::new(vec![]));
+        assert!(cfg.is_empty());
+        assert!(cfg.reverse_postorder().is_empty());
+    }
+
+    #[test]
+    fn dominators_follow_the_loop() {
+        let cfg = Cfg::new(&countdown());
+        assert_eq!(cfg.reverse_postorder(), vec![0, 1, 2]);
+        assert_eq!(
+            cfg.dominators(),
+            vec![
+                BTreeSet::from([0]),
+                BTreeSet::from([0, 1]),
+                BTreeSet::from([0, 1, 2])
+            ]
+        );
+    }
+
+    #[test]
+    fn branches_dominate_neither_side() {
+        // 0: jnz r0 -> 3, 2: jnz r1 -> 4, so block 3 (out r0) is reached
+        // from both
+        let program = Program::new(vec![set(0, 1), jnz(0, 3), jnz(1, 4), out(1), out(0)]);
+        let cfg = Cfg::new(&program);
+        let dominators = cfg.dominators();
+        let branch = cfg.block_of(2);
+        let target = cfg.block_of(4);
+        assert!(!dominators[target].contains(&branch));
+        assert!(dominators[target].contains(&cfg.block_of(0)));
+    }
+}
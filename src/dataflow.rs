@@ -0,0 +1,380 @@
+use crate::cfg::Cfg;
+use crate::Instruction;
+use crate::Program;
+use std::collections::{BTreeSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A dataflow problem over the CFG of a program. Facts flow along CFG edges
+/// in `direction()`, are merged with `join` where edges meet and are updated
+/// by `transfer` one instruction at a time.
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    /// Fact at program entry (forward) or at every exit (backward).
+    fn boundary(&self) -> Self::Fact;
+
+    /// Starting fact for every other block; the identity of `join`.
+    fn initial(&self) -> Self::Fact;
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact);
+
+    fn transfer(&self, index: usize, instruction: &Instruction, fact: &mut Self::Fact);
+}
+
+/// Per-instruction facts. `before` and `after` are in execution order
+/// regardless of the direction the analysis ran in.
+#[derive(Debug, Clone)]
+pub struct Solution<F> {
+    before: Vec<F>,
+    after: Vec<F>,
+}
+
+impl<F> Solution<F> {
+    pub fn before(&self, index: usize) -> &F {
+        &self.before[index]
+    }
+
+    pub fn after(&self, index: usize) -> &F {
+        &self.after[index]
+    }
+}
+
+pub fn solve<A: Analysis>(analysis: &A, program: &Program, cfg: &Cfg) -> Solution<A::Fact> {
+    let code: Vec<Instruction> = program.iter().map(|x| x.code()).collect();
+    let count = cfg.len();
+    let mut block_in = vec![analysis.initial(); count];
+    let mut block_out = vec![analysis.initial(); count];
+
+    let mut worklist: VecDeque<usize> = match analysis.direction() {
+        Direction::Forward => cfg.reverse_postorder().into(),
+        Direction::Backward => cfg.reverse_postorder().into_iter().rev().collect(),
+    };
+    // Unreachable blocks still get facts so every instruction has one.
+    for block in 0..count {
+        if !worklist.contains(&block) {
+            worklist.push_back(block);
+        }
+    }
+    let mut queued = vec![true; count];
+
+    while let Some(id) = worklist.pop_front() {
+        queued[id] = false;
+        let block = cfg.block(id);
+        match analysis.direction() {
+            Direction::Forward => {
+                let mut fact = analysis.initial();
+                if id == 0 {
+                    analysis.join(&mut fact, &analysis.boundary());
+                }
+                for pred in &block.predecessors {
+                    analysis.join(&mut fact, &block_out[*pred]);
+                }
+                block_in[id] = fact.clone();
+                for idx in block.range() {
+                    analysis.transfer(idx, &code[idx], &mut fact);
+                }
+                if fact != block_out[id] {
+                    block_out[id] = fact;
+                    for succ in &block.successors {
+                        if !queued[*succ] {
+                            queued[*succ] = true;
+                            worklist.push_back(*succ);
+                        }
+                    }
+                }
+            }
+            Direction::Backward => {
+                let mut fact = analysis.initial();
+                if block.exits {
+                    analysis.join(&mut fact, &analysis.boundary());
+                }
+                for succ in &block.successors {
+                    analysis.join(&mut fact, &block_in[*succ]);
+                }
+                block_out[id] = fact.clone();
+                for idx in block.range().rev() {
+                    analysis.transfer(idx, &code[idx], &mut fact);
+                }
+                if fact != block_in[id] {
+                    block_in[id] = fact;
+                    for pred in &block.predecessors {
+                        if !queued[*pred] {
+                            queued[*pred] = true;
+                            worklist.push_back(*pred);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut before = vec![analysis.initial(); code.len()];
+    let mut after = vec![analysis.initial(); code.len()];
+    for (id, block) in cfg.blocks().iter().enumerate() {
+        match analysis.direction() {
+            Direction::Forward => {
+                let mut fact = block_in[id].clone();
+                for idx in block.range() {
+                    before[idx] = fact.clone();
+                    analysis.transfer(idx, &code[idx], &mut fact);
+                    after[idx] = fact.clone();
+                }
+            }
+            Direction::Backward => {
+                let mut fact = block_out[id].clone();
+                for idx in block.range().rev() {
+                    after[idx] = fact.clone();
+                    analysis.transfer(idx, &code[idx], &mut fact);
+                    before[idx] = fact.clone();
+                }
+            }
+        }
+    }
+
+    Solution { before, after }
+}
+
+/// Registers whose current value may still be read.
+pub struct RegisterLiveness;
+
+impl Analysis for RegisterLiveness {
+    type Fact = BTreeSet<usize>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().copied());
+    }
+
+    fn transfer(&self, _index: usize, instruction: &Instruction, fact: &mut Self::Fact) {
+        for reg in instruction.defs() {
+            fact.remove(&reg);
+        }
+        fact.extend(instruction.uses());
+    }
+}
+
+/// Variables whose current value may still be loaded. Memory is not part of
+/// the observable output, so nothing is live at exit.
+pub struct VariableLiveness;
+
+impl Analysis for VariableLiveness {
+    type Fact = BTreeSet<usize>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().copied());
+    }
+
+    fn transfer(&self, _index: usize, instruction: &Instruction, fact: &mut Self::Fact) {
+        if let Some(variable) = instruction.writes_variable() {
+            fact.remove(&variable);
+        }
+        if let Some(variable) = instruction.reads_variable() {
+            fact.insert(variable);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Location {
+    Register(usize),
+    Variable(usize),
+}
+
+impl Location {
+    pub fn defined_by(instruction: &Instruction) -> Vec<Location> {
+        let mut locations: Vec<Location> = instruction
+            .defs()
+            .into_iter()
+            .map(Location::Register)
+            .collect();
+        if let Some(variable) = instruction.writes_variable() {
+            locations.push(Location::Variable(variable));
+        }
+        locations
+    }
+}
+
+/// Indices of the definitions that may reach each point. Every instruction
+/// that writes a register or variable is a definition (not only `SetReg` and
+/// `Store`), since any of them kills earlier definitions of the same location.
+/// A location with no reaching definition still holds its initial value.
+pub struct ReachingDefinitions {
+    defined: Vec<Vec<Location>>,
+}
+
+impl ReachingDefinitions {
+    pub fn new(program: &Program) -> Self {
+        Self {
+            defined: program
+                .iter()
+                .map(|x| Location::defined_by(&x.code()))
+                .collect(),
+        }
+    }
+
+    pub fn defines(&self, index: usize) -> &[Location] {
+        &self.defined[index]
+    }
+}
+
+impl Analysis for ReachingDefinitions {
+    type Fact = BTreeSet<usize>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().copied());
+    }
+
+    fn transfer(&self, index: usize, _instruction: &Instruction, fact: &mut Self::Fact) {
+        let killed = &self.defined[index];
+        if killed.is_empty() {
+            return;
+        }
+        fact.retain(|def| !self.defined[*def].iter().any(|l| killed.contains(l)));
+        fact.insert(index);
+    }
+}
+
+pub fn register_liveness(program: &Program, cfg: &Cfg) -> Solution<BTreeSet<usize>> {
+    solve(&RegisterLiveness, program, cfg)
+}
+
+pub fn variable_liveness(program: &Program, cfg: &Cfg) -> Solution<BTreeSet<usize>> {
+    solve(&VariableLiveness, program, cfg)
+}
+
+pub fn reaching_definitions(program: &Program, cfg: &Cfg) -> Solution<BTreeSet<usize>> {
+    solve(&ReachingDefinitions::new(program), program, cfg)
+}
+
+/// Whether each instruction has an effect that is observed later: it outputs,
+/// or it writes a register or variable that is live afterwards.
+pub fn observably_used(program: &Program, cfg: &Cfg) -> Vec<bool> {
+    let registers = register_liveness(program, cfg);
+    let variables = variable_liveness(program, cfg);
+    program
+        .iter()
+        .enumerate()
+        .map(|(idx, x)| {
+            let code = x.code();
+            matches!(code, Instruction::Output(_))
+                || code.defs().iter().any(|r| registers.after(idx).contains(r))
+                || code
+                    .writes_variable()
+                    .is_some_and(|v| variables.after(idx).contains(&v))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Counts r0 down from 3 and prints it.
+    fn countdown() -> Program {
+        Program::new(vec![set(0, 3), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)])
+    }
+
+    #[test]
+    fn registers_stay_live_around_the_loop() {
+        let program = countdown();
+        let live = register_liveness(&program, &Cfg::new(&program));
+        assert_eq!(*live.before(0), BTreeSet::new());
+        assert_eq!(*live.before(1), BTreeSet::from([0]));
+        assert_eq!(*live.before(2), BTreeSet::from([0, 1]));
+        assert_eq!(*live.after(3), BTreeSet::from([0, 1]));
+        assert_eq!(*live.after(4), BTreeSet::new());
+    }
+
+    #[test]
+    fn variables_are_live_until_overwritten() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 1),
+            store(0, 0),
+            load(1, 0),
+            store(1, 0),
+            out(1),
+        ]);
+        let live = variable_liveness(&program, &Cfg::new(&program));
+        assert_eq!(*live.after(2), BTreeSet::from([0]));
+        assert_eq!(*live.after(3), BTreeSet::new());
+        assert_eq!(*live.after(4), BTreeSet::new());
+    }
+
+    #[test]
+    fn definitions_reach_around_the_loop() {
+        let program = countdown();
+        let reaching = reaching_definitions(&program, &Cfg::new(&program));
+        assert_eq!(*reaching.before(2), BTreeSet::from([0, 1, 2]));
+        assert_eq!(*reaching.before(4), BTreeSet::from([1, 2]));
+    }
+
+    #[test]
+    fn stores_kill_earlier_stores() {
+        let program = Program::new(vec![var(0), set(0, 1), store(0, 0), store(0, 0)]);
+        let reaching = reaching_definitions(&program, &Cfg::new(&program));
+        assert_eq!(*reaching.after(3), BTreeSet::from([1, 3]));
+        assert_eq!(
+            ReachingDefinitions::new(&program).defines(0),
+            [Location::Variable(0)]
+        );
+    }
+
+    #[test]
+    fn overwritten_and_unread_writes_are_unused() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 1),
+            set(0, 2),
+            store(0, 0),
+            load(1, 0),
+            store(1, 0),
+            out(1),
+        ]);
+        let used = observably_used(&program, &Cfg::new(&program));
+        assert_eq!(used[1..], [false, true, true, true, false, true]);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::cfg::Cfg;
+use crate::op_finder::{alphabet, random_action, ProgramState};
+use crate::optimizer::{Budget, Meter, Optimized, Optimizer, Statistics};
+use crate::Program;
+use crate::VirtualMachine;
+use rand::prelude::*;
+use std::time::Instant;
+
+/// How one generation of the population did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub index: usize,
+    /// Executed cost of the cheapest member with the reference output.
+    pub best_cost: Option<usize>,
+    /// Mean executed cost over the members that run.
+    pub mean_cost: Option<f64>,
+}
+
+#[derive(Clone)]
+struct Member {
+    state: ProgramState,
+    reward: isize,
+    /// Executed cost, `None` if the program fails to run.
+    cost: Option<usize>,
+    correct: bool,
+}
+
+impl Member {
+    fn new(
+        mut state: ProgramState,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+    ) -> Self {
+        match state.exe(vm) {
+            Ok(()) => Self {
+                reward: state.reward(reference),
+                cost: state.executed_cost(),
+                correct: state.is_correct(&reference.1),
+                state,
+            },
+            Err(_) => Self {
+                state,
+                reward: isize::MIN,
+                cost: None,
+                correct: false,
+            },
+        }
+    }
+
+    /// Ordering for selection: reward first, then correct programs, then
+    /// cheaper ones, since every program that isn't an improvement gets the
+    /// same reward.
+    fn fitness(&self) -> (isize, bool, isize) {
+        let cost = self.cost.map_or(isize::MIN, |x| -(x as isize));
+        (self.reward, self.correct, cost)
+    }
+}
+
+/// A genetic algorithm over programs. Each generation keeps the `elitism`
+/// fittest members as they are and breeds the rest from parents picked by
+/// tournaments of `tournament_size`, ranked by `ProgramState::reward`.
+/// Children are spliced from two parents at basic block boundaries with
+/// probability `crossover_rate` and then, with probability `mutation_rate`,
+/// get a random `Action`. The budget's iterations are generations.
+pub struct Genetic {
+    pub population_size: usize,
+    pub tournament_size: usize,
+    pub elitism: usize,
+    pub crossover_rate: f64,
+    pub mutation_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Genetic {
+    fn default() -> Self {
+        Self {
+            population_size: 64,
+            tournament_size: 3,
+            elitism: 2,
+            crossover_rate: 0.7,
+            mutation_rate: 0.8,
+            seed: 0,
+        }
+    }
+}
+
+impl Genetic {
+    /// Runs the search, calling `report` with the first population and every
+    /// one bred from it. As an `Optimizer` it reports nothing.
+    pub fn evolve(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+        mut report: impl FnMut(&Generation),
+    ) -> Optimized {
+        let start = Instant::now();
+        let meter = Meter::new(budget);
+        let mut seen = (vm.executions(), vm.steps());
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let register_count = vm.register_count();
+        let alphabet = alphabet(program, register_count);
+        let mutate = |state: &ProgramState, rng: &mut StdRng| {
+            let action = random_action(state, &alphabet, register_count, rng);
+            state.applying(&vec![action])
+        };
+
+        let root = ProgramState::new(program.clone());
+        let mut population = vec![Member::new(root.clone(), vm, reference)];
+        while population.len() < self.population_size.max(1) {
+            let child = mutate(&root, &mut rng);
+            population.push(Member::new(child, vm, reference));
+        }
+
+        let mut best = program.clone();
+        let mut best_cost = vm.exe(program).map_or(reference.0, |(cost, _)| cost);
+        // Scores and reports every generation, the last bred one included
+        let mut record = |population: &[Member], index: usize| {
+            for member in population {
+                match member.cost {
+                    Some(cost) if member.correct && cost < best_cost => {
+                        best = member.state.program().clone();
+                        best_cost = cost;
+                    }
+                    _ => {}
+                }
+            }
+            let costs: Vec<usize> = population.iter().filter_map(|x| x.cost).collect();
+            report(&Generation {
+                index,
+                best_cost: population
+                    .iter()
+                    .filter(|x| x.correct)
+                    .filter_map(|x| x.cost)
+                    .min(),
+                mean_cost: (!costs.is_empty())
+                    .then(|| costs.iter().sum::<usize>() as f64 / costs.len() as f64),
+            });
+        };
+
+        let mut index = 0;
+        record(&population, index);
+        while !meter.exhausted(index) {
+            population.sort_by_key(|x| std::cmp::Reverse(x.fitness()));
+            let mut next: Vec<Member> = population.iter().take(self.elitism).cloned().collect();
+            while next.len() < population.len() {
+                let first = self.tournament(&population, &mut rng);
+                let mut child = if rng.gen_bool(self.crossover_rate) {
+                    let second = self.tournament(&population, &mut rng);
+                    crossover(first.state.program(), second.state.program(), &mut rng)
+                } else {
+                    first.state.clone()
+                };
+                if rng.gen_bool(self.mutation_rate) {
+                    child = mutate(&child, &mut rng);
+                }
+                next.push(Member::new(child, vm, reference));
+            }
+            population = next;
+            meter.charge((vm.executions(), vm.steps()), &mut seen);
+            index += 1;
+            record(&population, index);
+        }
+
+        Optimized {
+            program: best,
+            statistics: Statistics {
+                iterations: index,
+                original_cost: reference.0,
+                best_cost,
+                elapsed: start.elapsed(),
+                cache: None,
+            },
+        }
+    }
+
+    fn tournament<'a>(&self, population: &'a [Member], rng: &mut impl Rng) -> &'a Member {
+        population
+            .choose_multiple(rng, self.tournament_size.max(1))
+            .max_by_key(|x| x.fitness())
+            .unwrap()
+    }
+}
+
+/// The blocks of `first` before a random block boundary followed by the
+/// blocks of `second` from the boundary with the same index, or its last,
+/// with the jumps taken from `second` shifted to where its blocks ended up.
+fn crossover(first: &Program, second: &Program, rng: &mut impl Rng) -> ProgramState {
+    let boundaries = |program: &Program| {
+        let mut starts: Vec<usize> = Cfg::new(program).blocks().iter().map(|x| x.start).collect();
+        starts.push(program.len());
+        starts
+    };
+    let (first_boundaries, second_boundaries) = (boundaries(first), boundaries(second));
+    let block = rng.gen_range(0..first_boundaries.len());
+    let cut = first_boundaries[block];
+    let from = second_boundaries[block.min(second_boundaries.len() - 1)];
+
+    let mut child = first
+        .expand(|idx, x| if idx < cut { vec![*x] } else { vec![] })
+        .0;
+    for instruction in second.iter().skip(from) {
+        child.insert(child.len(), *instruction);
+    }
+    let child = child.retarget(|idx, target| {
+        if idx >= cut && target >= from {
+            target - from + cut
+        } else {
+            target
+        }
+    });
+    ProgramState::new(child)
+}
+
+impl Optimizer for Genetic {
+    fn name(&self) -> &'static str {
+        "genetic"
+    }
+
+    fn optimize(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+    ) -> Optimized {
+        self.evolve(program, vm, reference, budget, |_| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+
+    fn evolve(genetic: &Genetic) -> (Optimized, Vec<Generation>) {
+        let program = count_to_x::prog(10);
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&program).unwrap();
+        let mut generations = vec![];
+        let optimized = genetic.evolve(&program, &mut vm, &reference, Budget::iterations(5), |x| {
+            generations.push(x.clone())
+        });
+        (optimized, generations)
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let genetic = Genetic {
+            population_size: 16,
+            seed: 3,
+            ..Genetic::default()
+        };
+        let (first, generations) = evolve(&genetic);
+        let (second, again) = evolve(&genetic);
+        assert_eq!(first.program.code(), second.program.code());
+        assert_eq!(generations, again);
+    }
+
+    #[test]
+    fn every_generation_is_reported() {
+        let genetic = Genetic {
+            population_size: 8,
+            ..Genetic::default()
+        };
+        let (optimized, generations) = evolve(&genetic);
+        assert_eq!(optimized.statistics.iterations, 5);
+        let indices: Vec<usize> = generations.iter().map(|x| x.index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+        // The input program is always in the first generation
+        assert!(generations[0].best_cost.is_some());
+    }
+
+    #[test]
+    fn the_last_generation_is_scored() {
+        let genetic = Genetic {
+            population_size: 8,
+            ..Genetic::default()
+        };
+        let (optimized, generations) = evolve(&genetic);
+        let (last, earlier) = generations.split_last().unwrap();
+        let earlier = earlier.iter().filter_map(|x| x.best_cost).min().unwrap();
+        // With this seed the cheapest program only turns up in the last one
+        assert!(last.best_cost.unwrap() < earlier);
+        assert_eq!(Some(optimized.statistics.best_cost), last.best_cost);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::optimizer::{Budget, Optimizer};
+use crate::passes::{PassManager, PassReport, VerificationError};
+use crate::regalloc::{allocate, AllocationError};
+use crate::vm::ExecutionError;
+use crate::Program;
+use crate::VirtualMachine;
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum HybridError {
+    /// The input program doesn't run, so there is nothing to compare to.
+    Execution(ExecutionError),
+    Verification(VerificationError),
+    Allocation(AllocationError),
+}
+
+/// Where the savings of a hybrid run came from, all measured as executed
+/// cost on the VM.
+#[derive(Debug, Clone)]
+pub struct HybridReport {
+    pub program: Program,
+    pub original_cost: usize,
+    pub final_cost: usize,
+    pub before_search: PassReport,
+    /// Name of the search strategy.
+    pub strategy: &'static str,
+    /// Saving found by the search over the pre-optimised program.
+    pub search_saving: usize,
+    /// Passes re-run over the best program the search found, if it found
+    /// one.
+    pub after_search: Option<PassReport>,
+}
+
+impl HybridReport {
+    /// Negative when the passes made the executed cost worse.
+    pub fn before_search_saving(&self) -> isize {
+        self.before_search.executed_saving().unwrap_or(0)
+    }
+
+    pub fn after_search_saving(&self) -> isize {
+        self.after_search
+            .as_ref()
+            .and_then(|x| x.executed_saving())
+            .unwrap_or(0)
+    }
+}
+
+impl Display for HybridReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Cost: {} -> {}", self.original_cost, self.final_cost)?;
+        writeln!(
+            f,
+            "Passes before search: {:+}",
+            -self.before_search_saving()
+        )?;
+        writeln!(f, "Search ({}): -{}", self.strategy, self.search_saving)?;
+        writeln!(f, "Passes after search: {:+}", -self.after_search_saving())
+    }
+}
+
+/// Applies the classic pass pipeline, spends the search budget on what is
+/// left and then runs the pipeline again over the best program the search
+/// found.
+/// Programs written against more registers than the VM has are register
+/// allocated first.
+pub fn hybrid(
+    program: Program,
+    vm: &mut VirtualMachine,
+    optimizer: &dyn Optimizer,
+    budget: Budget,
+) -> Result<HybridReport, HybridError> {
+    let pipeline = PassManager::classic(vm.register_count());
+    let virtual_registers = program
+        .code()
+        .iter()
+        .flat_map(|x| x.uses().into_iter().chain(x.defs()))
+        .any(|x| x >= vm.register_count());
+    let program = if virtual_registers {
+        allocate(&program, vm.register_count())
+            .map_err(HybridError::Allocation)?
+            .program
+    } else {
+        program
+    };
+    let (original_cost, _) = vm.exe(&program).map_err(HybridError::Execution)?;
+
+    let before_search = pipeline
+        .run_checked(&program, vm)
+        .map_err(HybridError::Verification)?;
+    let basis = vm
+        .exe(&before_search.program)
+        .map_err(HybridError::Execution)?;
+
+    let searched = optimizer.optimize(&before_search.program, vm, &basis, budget);
+    let search_saving = searched.statistics.saving();
+    let after_search = if search_saving > 0 {
+        let report = pipeline
+            .run_checked(&searched.program, vm)
+            .map_err(HybridError::Verification)?;
+        Some(report)
+    } else {
+        None
+    };
+
+    let program = match &after_search {
+        Some(report) => report.program.clone(),
+        None => before_search.program.clone(),
+    };
+    let (final_cost, _) = vm.exe(&program).map_err(HybridError::Execution)?;
+
+    Ok(HybridReport {
+        program,
+        original_cost,
+        final_cost,
+        before_search,
+        strategy: optimizer.name(),
+        search_saving,
+        after_search,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op_finder::{Mcts, MctsConfig};
+    use crate::passes::PassStatistics;
+    use crate::testing::*;
+
+    fn report(executed_saving: isize) -> PassReport {
+        PassReport {
+            program: Program::new(vec![out(0)]),
+            iterations: 1,
+            reached_fixpoint: true,
+            passes: vec![PassStatistics {
+                name: "worse",
+                runs: 1,
+                changes: 1,
+                static_saving: 0,
+                executed_saving: Some(executed_saving),
+            }],
+        }
+    }
+
+    #[test]
+    fn reports_passes_that_cost_more_as_negative_savings() {
+        let report = HybridReport {
+            program: Program::new(vec![out(0)]),
+            original_cost: 10,
+            final_cost: 13,
+            before_search: report(-3),
+            strategy: "mcts",
+            search_saving: 0,
+            after_search: Some(report(2)),
+        };
+        assert_eq!(report.before_search_saving(), -3);
+        assert_eq!(report.after_search_saving(), 2);
+        let text = report.to_string();
+        assert!(text.contains("Passes before search: +3"));
+        assert!(text.contains("Passes after search: -2"));
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples_without_costing_more() {
+        let mcts = Mcts::new(MctsConfig {
+            rollout_depth: 16,
+            ..MctsConfig::default()
+        });
+        for (program, mut vm) in examples() {
+            let report = hybrid(program.clone(), &mut vm, &mcts, Budget::iterations(200)).unwrap();
+            let (before, after) = assert_same_output_on(&mut vm, &program, &report.program);
+            assert_eq!((report.original_cost, report.final_cost), (before, after));
+            assert!(after <= before, "{before} -> {after}");
+        }
+    }
+}
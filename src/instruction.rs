@@ -46,3 +46,200 @@ pub enum Instruction {
     // IO
     Output(usize),
 }
+
+impl Instruction {
+    /// Registers read by the instruction.
+    pub fn uses(&self) -> Vec<usize> {
+        match self {
+            Instruction::Add { rega, regb, .. } => vec![*rega, *regb],
+            Instruction::Sub { rega, regb, .. } => vec![*rega, *regb],
+            Instruction::Var(_) => vec![],
+            Instruction::Load { .. } => vec![],
+            Instruction::Store { register, .. } => vec![*register],
+            Instruction::SetReg { .. } => vec![],
+            Instruction::VecAdd {
+                a1r, b1r, a2r, b2r, ..
+            } => vec![*a1r, *b1r, *a2r, *b2r],
+            Instruction::PCSetIfNotZero { register, .. } => vec![*register],
+            Instruction::Output(register) => vec![*register],
+        }
+    }
+
+    /// Registers written by the instruction.
+    pub fn defs(&self) -> Vec<usize> {
+        match self {
+            Instruction::Add { outreg, .. } => vec![*outreg],
+            Instruction::Sub { outreg, .. } => vec![*outreg],
+            Instruction::Var(_) => vec![],
+            Instruction::Load { register, .. } => vec![*register],
+            Instruction::Store { .. } => vec![],
+            Instruction::SetReg { register, .. } => vec![*register],
+            Instruction::VecAdd { r1, r2, .. } => vec![*r1, *r2],
+            Instruction::PCSetIfNotZero { .. } => vec![],
+            Instruction::Output(_) => vec![],
+        }
+    }
+
+    /// Variable whose value is read by the instruction.
+    pub fn reads_variable(&self) -> Option<usize> {
+        match self {
+            Instruction::Load { variable, .. } => Some(*variable),
+            _ => None,
+        }
+    }
+
+    /// Variable whose value is overwritten by the instruction. `Var` counts
+    /// as a write since it (re)initialises the variable to 0.
+    pub fn writes_variable(&self) -> Option<usize> {
+        match self {
+            Instruction::Var(variable) => Some(*variable),
+            Instruction::Store { variable, .. } => Some(*variable),
+            _ => None,
+        }
+    }
+
+    pub fn is_branch(&self) -> bool {
+        matches!(self, Instruction::PCSetIfNotZero { .. })
+    }
+
+    /// Rewrites every register operand through `f`.
+    pub fn map_registers(&self, f: impl Fn(usize) -> usize) -> Instruction {
+        match *self {
+            Instruction::Add { rega, regb, outreg } => Instruction::Add {
+                rega: f(rega),
+                regb: f(regb),
+                outreg: f(outreg),
+            },
+            Instruction::Sub { rega, regb, outreg } => Instruction::Sub {
+                rega: f(rega),
+                regb: f(regb),
+                outreg: f(outreg),
+            },
+            Instruction::Var(variable) => Instruction::Var(variable),
+            Instruction::Load { register, variable } => Instruction::Load {
+                register: f(register),
+                variable,
+            },
+            Instruction::Store { register, variable } => Instruction::Store {
+                register: f(register),
+                variable,
+            },
+            Instruction::SetReg { register, constant } => Instruction::SetReg {
+                register: f(register),
+                constant,
+            },
+            Instruction::VecAdd {
+                a1r,
+                b1r,
+                r1,
+                a2r,
+                b2r,
+                r2,
+            } => Instruction::VecAdd {
+                a1r: f(a1r),
+                b1r: f(b1r),
+                r1: f(r1),
+                a2r: f(a2r),
+                b2r: f(b2r),
+                r2: f(r2),
+            },
+            Instruction::PCSetIfNotZero {
+                register,
+                jump_point,
+            } => Instruction::PCSetIfNotZero {
+                register: f(register),
+                jump_point,
+            },
+            Instruction::Output(register) => Instruction::Output(f(register)),
+        }
+    }
+
+    /// Rewrites the registers the instruction reads through `f`, leaving the
+    /// ones it writes alone.
+    pub fn map_uses(&self, f: impl Fn(usize) -> usize) -> Instruction {
+        match *self {
+            Instruction::Add { rega, regb, outreg } => Instruction::Add {
+                rega: f(rega),
+                regb: f(regb),
+                outreg,
+            },
+            Instruction::Sub { rega, regb, outreg } => Instruction::Sub {
+                rega: f(rega),
+                regb: f(regb),
+                outreg,
+            },
+            Instruction::Store { register, variable } => Instruction::Store {
+                register: f(register),
+                variable,
+            },
+            Instruction::VecAdd {
+                a1r,
+                b1r,
+                r1,
+                a2r,
+                b2r,
+                r2,
+            } => Instruction::VecAdd {
+                a1r: f(a1r),
+                b1r: f(b1r),
+                r1,
+                a2r: f(a2r),
+                b2r: f(b2r),
+                r2,
+            },
+            Instruction::PCSetIfNotZero {
+                register,
+                jump_point,
+            } => Instruction::PCSetIfNotZero {
+                register: f(register),
+                jump_point,
+            },
+            Instruction::Output(register) => Instruction::Output(f(register)),
+            Instruction::Var(_) | Instruction::Load { .. } | Instruction::SetReg { .. } => *self,
+        }
+    }
+
+    /// Rewrites the registers the instruction writes through `f`, leaving the
+    /// ones it reads alone.
+    pub fn map_defs(&self, f: impl Fn(usize) -> usize) -> Instruction {
+        match *self {
+            Instruction::Add { rega, regb, outreg } => Instruction::Add {
+                rega,
+                regb,
+                outreg: f(outreg),
+            },
+            Instruction::Sub { rega, regb, outreg } => Instruction::Sub {
+                rega,
+                regb,
+                outreg: f(outreg),
+            },
+            Instruction::Load { register, variable } => Instruction::Load {
+                register: f(register),
+                variable,
+            },
+            Instruction::SetReg { register, constant } => Instruction::SetReg {
+                register: f(register),
+                constant,
+            },
+            Instruction::VecAdd {
+                a1r,
+                b1r,
+                r1,
+                a2r,
+                b2r,
+                r2,
+            } => Instruction::VecAdd {
+                a1r,
+                b1r,
+                r1: f(r1),
+                a2r,
+                b2r,
+                r2: f(r2),
+            },
+            Instruction::Var(_)
+            | Instruction::Store { .. }
+            | Instruction::PCSetIfNotZero { .. }
+            | Instruction::Output(_) => *self,
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use crate::Instruction;
 
-static mut INSTR_ID: usize = 0;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static INSTR_ID: AtomicUsize = AtomicUsize::new(0);
 
 fn generate_new_id() -> usize {
-    let tmp = unsafe { INSTR_ID };
-    unsafe { INSTR_ID += 1 };
-    return tmp;
+    INSTR_ID.fetch_add(1, Ordering::Relaxed)
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -60,14 +60,7 @@ impl InstructionContainer {
                 jump_point: _,
             } => 10,
             Instruction::Output(_) => 1,
-            Instruction::VecAdd {
-                a1r,
-                b1r,
-                r1,
-                a2r,
-                b2r,
-                r2,
-            } => 1,
+            Instruction::VecAdd { .. } => 1,
         }
     }
 }
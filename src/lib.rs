@@ -0,0 +1,28 @@
+pub mod annealing;
+pub mod beam;
+pub mod cfg;
+pub mod dataflow;
+pub mod genetic;
+pub mod hybrid;
+pub mod instruction;
+pub mod instruction_container;
+pub mod loops;
+pub mod observer;
+pub mod op_finder;
+pub mod optimizer;
+pub mod passes;
+pub mod program;
+pub mod programs;
+pub mod regalloc;
+pub mod rules;
+pub mod ssa;
+pub mod superopt;
+#[cfg(test)]
+mod testing;
+pub mod transposition;
+pub mod vm;
+
+pub use instruction::Instruction;
+pub use instruction_container::InstructionContainer;
+pub use program::Program;
+pub use vm::VirtualMachine;
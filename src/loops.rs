@@ -0,0 +1,558 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{solve, Analysis};
+use crate::passes::constant_propagation::{ConstantAnalysis, Constants};
+use crate::Instruction;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A loop in the CFG: the blocks that can reach one of the latches without
+/// passing through the header, which dominates all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaturalLoop {
+    pub header: usize,
+    pub latches: Vec<usize>,
+    pub body: BTreeSet<usize>,
+}
+
+impl NaturalLoop {
+    /// Predecessors of the header from outside the loop.
+    pub fn entries(&self, cfg: &Cfg) -> Vec<usize> {
+        cfg.block(self.header)
+            .predecessors
+            .iter()
+            .copied()
+            .filter(|x| !self.body.contains(x))
+            .collect()
+    }
+}
+
+/// Natural loops, one per header, innermost (smallest) first.
+pub fn natural_loops(cfg: &Cfg) -> Vec<NaturalLoop> {
+    let dominators = cfg.dominators();
+    let reachable: BTreeSet<usize> = cfg.reverse_postorder().into_iter().collect();
+    let mut loops: BTreeMap<usize, NaturalLoop> = BTreeMap::new();
+
+    for &latch in &reachable {
+        for &header in &cfg.block(latch).successors {
+            if !dominators[latch].contains(&header) {
+                continue;
+            }
+            let found = loops.entry(header).or_insert_with(|| NaturalLoop {
+                header,
+                latches: vec![],
+                body: BTreeSet::from([header]),
+            });
+            found.latches.push(latch);
+            let mut stack = vec![latch];
+            while let Some(block) = stack.pop() {
+                if found.body.insert(block) {
+                    stack.extend(cfg.block(block).predecessors.iter().copied());
+                }
+            }
+        }
+    }
+
+    let mut loops: Vec<NaturalLoop> = loops.into_values().collect();
+    loops.sort_by_key(|x| x.body.len());
+    loops
+}
+
+/// How a register written in a loop body changes from one iteration to the
+/// next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Evolution {
+    /// Holds the same value at the end of every iteration.
+    Constant(i32),
+    /// Grows by a fixed step every iteration.
+    Induction(i64),
+    /// Recomputed every iteration from constant and induction registers.
+    Derived,
+    /// Loaded, or computed from something that isn't tracked.
+    Opaque,
+}
+
+/// A single-block loop closed by a `PCSetIfNotZero` back to its first
+/// instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountingLoop {
+    pub block: usize,
+    pub start: usize,
+    pub end: usize,
+    pub evolutions: BTreeMap<usize, Evolution>,
+    /// Iterations the body runs for, when the registers the exit condition
+    /// depends on are known on entry and it terminates without overflowing.
+    pub trip_count: Option<u64>,
+    /// How much the exit condition changes from one iteration to the next,
+    /// when it only reads induction registers and registers the body never
+    /// writes.
+    pub condition_step: Option<i64>,
+    /// Values of every register written in the body once the loop exits,
+    /// for bodies of only `SetReg`/`Add`/`Sub`.
+    pub final_values: Option<BTreeMap<usize, i32>>,
+}
+
+// Register values as an affine combination of the values at the top of
+// the iteration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Linear {
+    coeffs: BTreeMap<usize, i128>,
+    constant: i128,
+}
+
+impl Linear {
+    fn constant(constant: i128) -> Self {
+        Self {
+            coeffs: BTreeMap::new(),
+            constant,
+        }
+    }
+
+    fn symbol(register: usize) -> Self {
+        Self {
+            coeffs: BTreeMap::from([(register, 1)]),
+            constant: 0,
+        }
+    }
+
+    fn combine(&self, other: &Linear, sign: i128) -> Self {
+        let mut out = self.clone();
+        for (register, coeff) in &other.coeffs {
+            *out.coeffs.entry(*register).or_insert(0) += sign * coeff;
+        }
+        out.coeffs.retain(|_, c| *c != 0);
+        out.constant += sign * other.constant;
+        out
+    }
+
+    /// Folds in the symbols `value` knows.
+    fn substitute(&self, value: impl Fn(usize) -> Option<i128>) -> Self {
+        let mut out = Linear::constant(self.constant);
+        for (register, coeff) in &self.coeffs {
+            match value(*register) {
+                Some(known) => out.constant += coeff * known,
+                None => {
+                    out.coeffs.insert(*register, *coeff);
+                }
+            }
+        }
+        out
+    }
+
+    fn eval(&self, state: impl Fn(usize) -> Option<i128>) -> Option<i128> {
+        let mut value = self.constant;
+        for (register, coeff) in &self.coeffs {
+            value += coeff * state(*register)?;
+        }
+        Some(value)
+    }
+}
+
+struct Body {
+    // Value each written register holds when the iteration ends, `None`
+    // where it doesn't follow from the values at the top
+    ends: BTreeMap<usize, Option<Linear>>,
+    // Every tracked value an instruction writes, for overflow checks
+    intermediates: Vec<Linear>,
+    condition: Option<Linear>,
+    // Nothing but `SetReg`/`Add`/`Sub`, so only registers change
+    pure: bool,
+}
+
+fn symbolic_body(code: &[Instruction]) -> Option<Body> {
+    let (branch, body) = code.split_last()?;
+    let Instruction::PCSetIfNotZero { register, .. } = branch else {
+        return None;
+    };
+
+    let mut ends: BTreeMap<usize, Option<Linear>> = BTreeMap::new();
+    let mut intermediates = vec![];
+    let mut pure = true;
+    let read = |ends: &BTreeMap<usize, Option<Linear>>, r: usize| {
+        ends.get(&r)
+            .cloned()
+            .unwrap_or_else(|| Some(Linear::symbol(r)))
+    };
+    let combine = |a: Option<Linear>, b: Option<Linear>, sign: i128| Some(a?.combine(&b?, sign));
+    for instruction in body {
+        let (out, value) = match *instruction {
+            Instruction::SetReg { register, constant } => {
+                (register, Some(Linear::constant(constant as i128)))
+            }
+            Instruction::Add { rega, regb, outreg } => {
+                (outreg, combine(read(&ends, rega), read(&ends, regb), 1))
+            }
+            Instruction::Sub { rega, regb, outreg } => {
+                (outreg, combine(read(&ends, rega), read(&ends, regb), -1))
+            }
+            _ => {
+                pure = false;
+                for register in instruction.defs() {
+                    ends.insert(register, None);
+                }
+                continue;
+            }
+        };
+        intermediates.extend(value.clone());
+        ends.insert(out, value);
+    }
+    let condition = read(&ends, *register);
+    Some(Body {
+        ends,
+        intermediates,
+        condition,
+        pure,
+    })
+}
+
+fn classify(ends: &BTreeMap<usize, Option<Linear>>) -> BTreeMap<usize, Evolution> {
+    let mut evolutions = BTreeMap::new();
+    for (register, end) in ends {
+        let evolution = match end {
+            None => Evolution::Opaque,
+            Some(end) if end.coeffs.is_empty() => match end.constant.try_into() {
+                Ok(constant) => Evolution::Constant(constant),
+                Err(_) => Evolution::Opaque,
+            },
+            Some(end) if end.coeffs.len() == 1 && end.coeffs.get(register) == Some(&1) => {
+                match end.constant.try_into() {
+                    Ok(step) => Evolution::Induction(step),
+                    Err(_) => Evolution::Opaque,
+                }
+            }
+            Some(_) => continue,
+        };
+        evolutions.insert(*register, evolution);
+    }
+    // Derived registers may only read registers with a simple evolution
+    let simple: BTreeMap<usize, Evolution> = evolutions.clone();
+    for (register, end) in ends {
+        if simple.contains_key(register) {
+            continue;
+        }
+        let derived = end.iter().flat_map(|x| x.coeffs.keys()).all(|x| {
+            !ends.contains_key(x)
+                || matches!(
+                    simple.get(x),
+                    Some(Evolution::Constant(_) | Evolution::Induction(_))
+                )
+        });
+        let evolution = if derived {
+            Evolution::Derived
+        } else {
+            Evolution::Opaque
+        };
+        evolutions.insert(*register, evolution);
+    }
+    evolutions
+}
+
+fn fits(value: i128) -> bool {
+    i32::try_from(value).is_ok()
+}
+
+/// Runs the loop from the known `entry` state, returning the trip count and,
+/// for pure bodies, the final value of every register the body writes.
+fn evaluate(
+    body: &Body,
+    evolutions: &BTreeMap<usize, Evolution>,
+    entry: &Constants,
+) -> Option<(u64, Option<BTreeMap<usize, i32>>)> {
+    let condition = body.condition.as_ref()?;
+    let mut initial = BTreeMap::new();
+    let expressions = body
+        .ends
+        .values()
+        .flatten()
+        .chain(body.intermediates.iter());
+    for expression in expressions.chain([condition]) {
+        for symbol in expression.coeffs.keys() {
+            if let Some(value) = entry.register(*symbol) {
+                initial.insert(*symbol, value as i128);
+            }
+        }
+    }
+
+    // Register values at the top of iteration `k`. Constant and induction
+    // registers are affine in `k` from the second iteration on, derived ones
+    // from the third, and so is everything computed from them.
+    let simple = |k: i128, r: usize| match evolutions.get(&r) {
+        Some(Evolution::Constant(c)) if k > 0 => Some(*c as i128),
+        Some(Evolution::Induction(step)) => Some(initial.get(&r)? + k * *step as i128),
+        Some(_) if k > 0 => None,
+        _ => initial.get(&r).copied(),
+    };
+    let start = |k: i128| {
+        move |r: usize| match evolutions.get(&r) {
+            Some(Evolution::Derived) if k > 0 => body.ends[&r].as_ref()?.eval(|x| simple(k - 1, x)),
+            _ => simple(k, r),
+        }
+    };
+    const AFFINE_FROM: i128 = 2;
+
+    let mut last = None;
+    for k in 0..=AFFINE_FROM {
+        if condition.eval(start(k))? == 0 {
+            last = Some(k);
+            break;
+        }
+    }
+    let last = match last {
+        Some(last) => last,
+        None => {
+            let at = condition.eval(start(AFFINE_FROM))?;
+            let delta = condition.eval(start(AFFINE_FROM + 1))? - at;
+            if delta == 0 || (-at) % delta != 0 || (-at) / delta < 0 {
+                return None;
+            }
+            AFFINE_FROM + (-at) / delta
+        }
+    };
+
+    // Past the affine point checking the ends of the range covers
+    // everything in between
+    let mut checked: Vec<i128> = (0..=last.min(AFFINE_FROM)).collect();
+    checked.push(last);
+    for k in checked {
+        let overflows = body
+            .intermediates
+            .iter()
+            .filter_map(|x| x.eval(start(k)))
+            .any(|x| !fits(x));
+        if overflows {
+            return None;
+        }
+    }
+
+    let finals = if body.pure {
+        body.ends
+            .iter()
+            .map(|(register, end)| {
+                let value = i32::try_from(end.as_ref()?.eval(start(last))?).ok()?;
+                Some((*register, value))
+            })
+            .collect::<Option<BTreeMap<usize, i32>>>()
+    } else {
+        None
+    };
+    Some((u64::try_from(last + 1).ok()?, finals))
+}
+
+/// Recognises single-block induction loops and evaluates the ones whose
+/// exit condition only depends on values known on entry.
+pub fn counting_loops(program: &Program, cfg: &Cfg) -> Vec<CountingLoop> {
+    let code = program.code();
+    let constants = solve(&ConstantAnalysis, program, cfg);
+    let mut found = vec![];
+
+    for natural in natural_loops(cfg) {
+        if natural.body.len() != 1 {
+            continue;
+        }
+        let block = cfg.block(natural.header);
+        let Some(mut body) = symbolic_body(&code[block.range()]) else {
+            continue;
+        };
+        match code[block.end - 1] {
+            Instruction::PCSetIfNotZero { jump_point, .. } if jump_point == block.start => {}
+            _ => continue,
+        }
+
+        let mut entry = None;
+        if natural.header == 0 {
+            ConstantAnalysis.join(&mut entry, &ConstantAnalysis.boundary());
+        }
+        for pred in natural.entries(cfg) {
+            ConstantAnalysis.join(&mut entry, constants.after(cfg.block(pred).end - 1));
+        }
+        // Registers the body never writes keep their entry value throughout
+        if let Some(entry) = &entry {
+            let written: BTreeSet<usize> = body.ends.keys().copied().collect();
+            let invariant = |r: usize| {
+                if written.contains(&r) {
+                    None
+                } else {
+                    entry.register(r).map(i128::from)
+                }
+            };
+            for end in body.ends.values_mut().flatten() {
+                *end = end.substitute(invariant);
+            }
+            for value in body.intermediates.iter_mut() {
+                *value = value.substitute(invariant);
+            }
+            if let Some(condition) = body.condition.as_mut() {
+                *condition = condition.substitute(invariant);
+            }
+        }
+        let evolutions = classify(&body.ends);
+        let condition_step = body.condition.as_ref().and_then(|condition| {
+            let mut step = 0;
+            for (register, coeff) in &condition.coeffs {
+                match evolutions.get(register) {
+                    None if !body.ends.contains_key(register) => {}
+                    Some(Evolution::Induction(x)) => step += coeff * i128::from(*x),
+                    _ => return None,
+                }
+            }
+            i64::try_from(step).ok().filter(|x| *x != 0)
+        });
+        let evaluated = entry.and_then(|entry| evaluate(&body, &evolutions, &entry));
+
+        found.push(CountingLoop {
+            block: natural.header,
+            start: block.start,
+            end: block.end,
+            evolutions,
+            trip_count: evaluated.as_ref().map(|x| x.0),
+            condition_step,
+            final_values: evaluated.and_then(|x| x.1),
+        });
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+
+    fn single(program: &Program) -> CountingLoop {
+        let found = counting_loops(program, &Cfg::new(program));
+        assert_eq!(found.len(), 1, "{found:?}");
+        found[0].clone()
+    }
+
+    #[test]
+    fn finds_the_natural_loop_of_a_countdown() {
+        let program = Program::new(vec![set(0, 3), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)]);
+        let loops = natural_loops(&Cfg::new(&program));
+        assert_eq!(
+            loops,
+            vec![NaturalLoop {
+                header: 1,
+                latches: vec![1],
+                body: BTreeSet::from([1]),
+            }]
+        );
+        assert_eq!(loops[0].entries(&Cfg::new(&program)), vec![0]);
+    }
+
+    #[test]
+    fn evaluates_a_countdown() {
+        let program = Program::new(vec![set(0, 5), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)]);
+        let found = single(&program);
+        assert_eq!((found.start, found.end), (2, 4));
+        assert_eq!(
+            found.evolutions,
+            BTreeMap::from([(0, Evolution::Induction(-1))])
+        );
+        assert_eq!(found.trip_count, Some(5));
+        assert_eq!(found.condition_step, Some(-1));
+        assert_eq!(found.final_values, Some(BTreeMap::from([(0, 0)])));
+    }
+
+    #[test]
+    fn evaluates_count_to_x() {
+        let found = single(&count_to_x::prog(10));
+        assert_eq!(found.evolutions[&0], Evolution::Induction(1));
+        assert_eq!(found.evolutions[&1], Evolution::Derived);
+        assert_eq!(found.trip_count, Some(10));
+        assert_eq!(found.final_values, Some(BTreeMap::from([(0, 10), (1, 0)])));
+    }
+
+    #[test]
+    fn tracks_constants_and_derived_registers() {
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            sub(0, 1, 0),
+            set(3, 7),
+            add(0, 0, 2),
+            jnz(0, 2),
+            out(2),
+        ]);
+        let found = single(&program);
+        assert_eq!(found.evolutions[&3], Evolution::Constant(7));
+        assert_eq!(found.evolutions[&2], Evolution::Derived);
+        assert_eq!(found.trip_count, Some(3));
+        assert_eq!(
+            found.final_values,
+            Some(BTreeMap::from([(0, 0), (2, 0), (3, 7)]))
+        );
+    }
+
+    #[test]
+    fn gives_up_on_overflow() {
+        // r3 passes i32::MAX on the third iteration
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            set(2, 1_000_000_000),
+            set(3, 0),
+            sub(0, 1, 0),
+            add(3, 2, 3),
+            jnz(0, 4),
+            out(3),
+        ]);
+        let found = single(&program);
+        assert_eq!(found.evolutions[&3], Evolution::Induction(1_000_000_000));
+        assert_eq!(found.trip_count, None);
+        assert_eq!(found.final_values, None);
+    }
+
+    #[test]
+    fn gives_up_when_the_condition_skips_zero() {
+        // 3, 1, -1, ... never reaches 0
+        let program = Program::new(vec![set(0, 3), set(1, 2), sub(0, 1, 0), jnz(0, 2), out(0)]);
+        let found = single(&program);
+        assert_eq!(found.trip_count, None);
+        assert_eq!(found.condition_step, Some(-2));
+    }
+
+    #[test]
+    fn gives_up_when_the_condition_moves_away_from_zero() {
+        let program = Program::new(vec![set(0, 3), set(1, 1), add(0, 1, 0), jnz(0, 2), out(0)]);
+        assert_eq!(single(&program).trip_count, None);
+    }
+
+    #[test]
+    fn needs_the_inputs_on_entry() {
+        let program = Program::new(vec![
+            var(0),
+            load(0, 0),
+            set(1, 1),
+            sub(0, 1, 0),
+            jnz(0, 3),
+            out(0),
+        ]);
+        let found = single(&program);
+        assert_eq!(found.evolutions[&0], Evolution::Induction(-1));
+        assert_eq!(found.condition_step, Some(-1));
+        assert_eq!(found.trip_count, None);
+    }
+
+    #[test]
+    fn loaded_conditions_are_opaque() {
+        let program = Program::new(vec![var(0), set(0, 1), load(0, 0), jnz(0, 2), out(0)]);
+        let found = single(&program);
+        assert_eq!(found.evolutions[&0], Evolution::Opaque);
+        assert_eq!(found.condition_step, None);
+        assert_eq!(found.trip_count, None);
+    }
+
+    #[test]
+    fn rejects_loops_over_several_blocks() {
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            sub(0, 1, 0),
+            jnz(1, 4),
+            jnz(0, 2),
+            out(0),
+        ]);
+        let cfg = Cfg::new(&program);
+        assert_eq!(natural_loops(&cfg).len(), 1);
+        assert!(counting_loops(&program, &cfg).is_empty());
+    }
+}
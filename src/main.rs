@@ -1,20 +1,41 @@
-use std::collections::HashMap;
-
-use crate::instruction_container::InstructionContainer;
-use crate::op_finder::mcts;
-use crate::program::Program;
-use crate::programs::{add_two, count_to_x, vecmul};
-use instruction::Instruction;
-use vm::VirtualMachine;
-
-mod instruction;
-mod instruction_container;
-mod op_finder;
-mod program;
-mod programs;
-mod vm;
+use m_prime::hybrid::hybrid;
+use m_prime::op_finder::{Mcts, MctsConfig};
+use m_prime::optimizer::{by_name, Budget};
+use m_prime::passes::Pass;
+use m_prime::programs::count_to_x;
+use m_prime::rules::mine::RuleDatabase;
+use m_prime::rules::parse_rules;
+use m_prime::rules::verify::Verifier;
+use m_prime::superopt::Superoptimizer;
+use m_prime::VirtualMachine;
+use std::time::Duration;
+
+const MINED_RULES: &str = "rules/mined.rules";
+const SUPEROPT_CACHE: &str = "rules/superopt.rules";
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(idx) = args.iter().position(|x| x == "--check-rules") {
+        let path = args.get(idx + 1).expect("Expected a rule file");
+        let text = std::fs::read_to_string(path).expect("Unable to read rule file");
+        let rules = parse_rules(&text).unwrap_or_else(|e| panic!("{path}: {e}"));
+        let verifier = Verifier::new();
+        let mut failed = false;
+        for rule in &rules {
+            match verifier.check(rule) {
+                Ok(instances) => println!("{}: ok ({instances} instances)", rule.name),
+                Err(counterexample) => {
+                    println!("{counterexample}");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let program = count_to_x::prog(1000);
 
     // let mut base_memory = HashMap::<usize, i32>::new();
@@ -31,11 +52,106 @@ fn main() {
     println!("{basis:?}");
     println!("{program}");
 
-    let op = mcts(program, &mut process, &basis, 50_000, 20_000);
+    let strategy = match args.iter().position(|x| x == "--strategy") {
+        Some(idx) => args.get(idx + 1).expect("Expected a strategy").as_str(),
+        None => "mcts",
+    };
+    let optimizer = match args.iter().position(|x| x == "--threads") {
+        Some(idx) => {
+            assert_eq!(strategy, "mcts", "--threads only applies to mcts");
+            let threads = args
+                .get(idx + 1)
+                .and_then(|x| x.parse().ok())
+                .expect("Expected a thread count");
+            Box::new(Mcts::new(MctsConfig {
+                threads,
+                ..MctsConfig::default()
+            }))
+        }
+        None => by_name(strategy).unwrap_or_else(|| panic!("Unknown strategy {strategy}")),
+    };
+    let budget = match args.iter().position(|x| x == "--seconds") {
+        Some(idx) => Budget::time(Duration::from_secs_f64(
+            args.get(idx + 1)
+                .and_then(|x| x.parse().ok())
+                .expect("Expected a number of seconds"),
+        )),
+        None => Budget::iterations(50_000),
+    };
+
+    if args.iter().any(|x| x == "--hybrid") {
+        let report =
+            hybrid(program, &mut process, optimizer.as_ref(), budget).expect("Error in hybrid");
+        println!("=============");
+        println!("{report}");
+        println!("Optimised program: \n\n{}\n", report.program);
+        return;
+    }
+
+    if args.iter().any(|x| x == "--superopt") {
+        let superoptimizer = Superoptimizer::new()
+            .with_cache(SUPEROPT_CACHE)
+            .expect("Invalid superoptimiser cache");
+        let optimised = superoptimizer.run(&program);
+        superoptimizer
+            .save()
+            .expect("Unable to save superoptimiser cache");
+        println!("=============");
+        println!("{:?}", process.exe(&optimised));
+        println!("Optimised program: \n\n{optimised}\n");
+        return;
+    }
+
+    // Mined rules are only read with --mined-rules and only written with
+    // --save-rules
+    let use_mined = args.iter().any(|x| x == "--mined-rules");
+    let save_mined = args.iter().any(|x| x == "--save-rules");
+    let mut database = (use_mined || save_mined).then(|| {
+        RuleDatabase::open(MINED_RULES).unwrap_or_else(|e| exit_with(format!("{MINED_RULES}: {e}")))
+    });
+
+    // Start from what earlier searches already found, and measure the search
+    // against the rewritten program so the two savings stay apart
+    let (program, basis) = match database.as_ref().filter(|_| use_mined) {
+        Some(database) => {
+            let rewritten = database.peephole().run(&program);
+            match process.exe(&rewritten) {
+                Ok(after) if after.1 == basis.1 => {
+                    if after.0 < basis.0 {
+                        println!("Mined rules saved {}", basis.0 - after.0);
+                    }
+                    (rewritten, after)
+                }
+                Ok(_) => exit_with(format!("{MINED_RULES}: the rules changed the output")),
+                Err(e) => exit_with(format!(
+                    "{MINED_RULES}: the rewritten program failed: {e:?}"
+                )),
+            }
+        }
+        None => (program, basis),
+    };
+
+    let optimized = optimizer.optimize(&program, &mut process, &basis, budget);
     println!("=============");
-    if let Some(op) = op {
+    println!("{}", optimized.statistics);
+    if optimized.statistics.saving() > 0 {
         println!("Optimised!");
-        println!("Optimisation amount: {}", op.0);
-        println!("Optimised program: \n\n{}\n", op.1.unwrap());
+        println!("Optimisation amount: {}", optimized.statistics.saving());
+        let optimised = optimized.program;
+        println!("Optimised program: \n\n{}\n", optimised);
+
+        if let Some(database) = database.as_mut().filter(|_| save_mined) {
+            let added = database.record(&program, &optimised);
+            database
+                .save()
+                .unwrap_or_else(|e| exit_with(format!("{MINED_RULES}: {e}")));
+            println!("Mined {added} new rules into {MINED_RULES}");
+        }
     }
 }
+
+/// Reports a problem with the mined rules and stops.
+fn exit_with(message: String) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1)
+}
@@ -0,0 +1,84 @@
+use crate::op_finder::Action;
+use crate::optimizer::CacheStatistics;
+use crate::Program;
+use std::time::Duration;
+
+/// Something that happened during an MCTS search. `tree` is the index of the
+/// tree it happened in, which is always 0 unless `MctsConfig::threads` is
+/// more than 1.
+#[derive(Debug, Clone, Copy)]
+pub enum SearchEvent<'a> {
+    EpochFinished {
+        tree: usize,
+        epoch: usize,
+        /// Best saving the tree has found so far.
+        best_saving: u32,
+    },
+    NewBest {
+        tree: usize,
+        epoch: usize,
+        saving: u32,
+        program: &'a Program,
+        /// Actions that turn the input program into `program`.
+        actions: &'a [Action],
+    },
+    /// Sent every `MctsConfig::snapshot_interval` epochs.
+    Snapshot {
+        tree: usize,
+        epoch: usize,
+        best_saving: u32,
+        elapsed: Duration,
+        cache: CacheStatistics,
+    },
+}
+
+/// Receives the events of a search. Trees searched in parallel share one
+/// observer, so it must be `Sync`. Any `Fn(&SearchEvent)` is an observer.
+pub trait SearchObserver: Sync {
+    fn notify(&self, event: &SearchEvent);
+}
+
+impl<F: Fn(&SearchEvent) + Sync> SearchObserver for F {
+    fn notify(&self, event: &SearchEvent) {
+        self(event)
+    }
+}
+
+/// Prints new best programs and snapshots to stdout, but not every epoch.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsoleReporter;
+
+impl SearchObserver for ConsoleReporter {
+    fn notify(&self, event: &SearchEvent) {
+        match event {
+            SearchEvent::EpochFinished { .. } => {}
+            SearchEvent::NewBest {
+                tree,
+                epoch,
+                saving,
+                actions,
+                ..
+            } => println!(
+                "Tree: {tree}, epoch: {epoch}, new best saving {saving} after {} actions",
+                actions.len()
+            ),
+            SearchEvent::Snapshot {
+                tree,
+                epoch,
+                best_saving,
+                elapsed,
+                cache,
+            } => println!(
+                "Tree: {tree}, epoch: {epoch}, best saving {best_saving} ({elapsed:.2?}), {cache}"
+            ),
+        }
+    }
+}
+
+/// Ignores every event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopReporter;
+
+impl SearchObserver for NoopReporter {
+    fn notify(&self, _event: &SearchEvent) {}
+}
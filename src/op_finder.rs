@@ -1,13 +1,25 @@
+use crate::cfg::Cfg;
+use crate::dataflow::observably_used;
 use crate::instruction::Instruction;
 use crate::instruction_container::InstructionContainer;
+use crate::loops::counting_loops;
+use crate::observer::{ConsoleReporter, NoopReporter, SearchEvent, SearchObserver};
+use crate::optimizer::{Budget, CacheStatistics, Meter, Optimized, Optimizer, Statistics};
+use crate::passes::unroll::{spare_register, unroll};
+use crate::transposition::{NodeStatistics, TranspositionTable};
 use crate::vm::ExecutionError;
 use crate::Program;
 use crate::VirtualMachine;
 use rand::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+const UNROLL_FACTORS: [usize; 2] = [2, 4];
+const FULL_UNROLL_SIZE: u64 = 64;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
-struct ProgramState {
+pub struct ProgramState {
     program: Program,
     out: Option<(usize, Vec<String>)>,
 }
@@ -16,74 +28,69 @@ struct ProgramState {
 //
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-enum Action {
+pub enum Action {
     Remove(usize),
     Replace(usize, Instruction),
     Add(usize, Instruction),
     Move(usize, usize),
+    /// Unroll the counting loop starting at the index by a factor.
+    Unroll(usize, usize),
     Nothing,
 }
 
+/// Families of `Action` a search may offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    /// Remove an instruction with no observed effect.
+    RemoveUnused,
+    /// Remove any instruction.
+    Remove,
+    /// Swap an instruction for a variant over other registers.
+    Replace,
+    /// Unroll a counting loop.
+    Unroll,
+}
+
+pub const DEFAULT_ACTIONS: [ActionKind; 2] = [ActionKind::Remove, ActionKind::Unroll];
+
 impl Instruction {
-    fn instruction_replacements(&self) -> Vec<Instruction> {
+    /// Every variant of the instruction over the `register_count` physical
+    /// registers of the VM.
+    pub fn instruction_replacements(&self, register_count: usize) -> Vec<Instruction> {
+        let registers = 0..register_count;
+        let triples = || {
+            (0..register_count).flat_map(move |a| {
+                (0..register_count)
+                    .flat_map(move |b| (0..register_count).map(move |out| (a, b, out)))
+            })
+        };
         match self {
-            Instruction::Add { rega, regb, outreg } => (0..4)
-                .flat_map(|a| {
-                    (0..4)
-                        .flat_map(|b| {
-                            (0..4)
-                                .map(|o| Instruction::Add {
-                                    rega: a,
-                                    regb: b,
-                                    outreg: 0,
-                                })
-                                .collect::<Vec<Instruction>>()
-                        })
-                        .collect::<Vec<Instruction>>()
-                })
+            Instruction::Add { .. } => triples()
+                .map(|(rega, regb, outreg)| Instruction::Add { rega, regb, outreg })
                 .collect(),
-            Instruction::Sub { rega, regb, outreg } => (0..4)
-                .flat_map(|a| {
-                    (0..4)
-                        .flat_map(|b| {
-                            (0..4)
-                                .map(|o| Instruction::Sub {
-                                    rega: a,
-                                    regb: b,
-                                    outreg: 0,
-                                })
-                                .collect::<Vec<Instruction>>()
-                        })
-                        .collect::<Vec<Instruction>>()
-                })
+            Instruction::Sub { .. } => triples()
+                .map(|(rega, regb, outreg)| Instruction::Sub { rega, regb, outreg })
                 .collect(),
             Instruction::Var(a) => vec![Instruction::Var(*a)],
-            Instruction::Load { register, variable } => (0..4)
+            Instruction::Load { variable, .. } => registers
                 .map(|i| Instruction::Load {
                     register: i,
                     variable: *variable,
                 })
                 .collect(),
-            Instruction::Store { register, variable } => (0..4)
+            Instruction::Store { variable, .. } => registers
                 .map(|i| Instruction::Store {
                     register: i,
                     variable: *variable,
                 })
                 .collect(),
-            Instruction::SetReg { register, constant } => (0..4)
+            Instruction::SetReg { constant, .. } => registers
                 .map(|i| Instruction::SetReg {
                     register: i,
                     constant: *constant,
                 })
                 .collect(),
-            Instruction::VecAdd {
-                a1r,
-                b1r,
-                r1,
-                a2r,
-                b2r,
-                r2,
-            } => vec![],
+            Instruction::VecAdd { .. } => vec![],
             Instruction::PCSetIfNotZero {
                 register,
                 jump_point,
@@ -91,12 +98,7 @@ impl Instruction {
                 register: *register,
                 jump_point: *jump_point,
             }],
-            Instruction::Output(_) => vec![
-                Instruction::Output(0),
-                Instruction::Output(1),
-                Instruction::Output(2),
-                Instruction::Output(3),
-            ],
+            Instruction::Output(_) => registers.map(Instruction::Output).collect(),
         }
     }
 }
@@ -108,6 +110,7 @@ impl Program {
                 self.remove(*idx);
             }
             Action::Replace(idx, new) => {
+                self.remove(*idx);
                 self.insert(*idx, InstructionContainer::new(*new));
             }
             Action::Add(idx, new) => {
@@ -118,6 +121,11 @@ impl Program {
                 let rem = InstructionContainer::new(self.remove(*from));
                 self.insert(*to, rem);
             }
+            Action::Unroll(start, factor) => {
+                if let Some(unrolled) = unroll(&self, *start, *factor) {
+                    self = unrolled;
+                }
+            }
         };
         self
     }
@@ -132,11 +140,11 @@ impl Program {
 }
 
 impl ProgramState {
-    fn new(program: Program) -> Self {
+    pub fn new(program: Program) -> Self {
         Self { out: None, program }
     }
 
-    fn exe(&mut self, vm: &mut VirtualMachine) -> Result<(), ExecutionError> {
+    pub fn exe(&mut self, vm: &mut VirtualMachine) -> Result<(), ExecutionError> {
         match vm.exe(&self.program) {
             Ok(o) => {
                 self.out = Some(o);
@@ -146,17 +154,31 @@ impl ProgramState {
         }
     }
 
-    fn applying(&self, actions: &Vec<Action>) -> Self {
+    pub fn applying(&self, actions: &Vec<Action>) -> Self {
         let mut new = self.clone();
         new.program = new.program.apply(actions);
         new
     }
 
-    fn requires_exe(&self) -> bool {
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Cost and output of the last execution, `None` before one succeeded.
+    pub fn output(&self) -> Option<&(usize, Vec<String>)> {
+        self.out.as_ref()
+    }
+
+    /// Cost of the last execution, `None` before one succeeded.
+    pub fn executed_cost(&self) -> Option<usize> {
+        self.out.as_ref().map(|x| x.0)
+    }
+
+    pub fn requires_exe(&self) -> bool {
         self.out.is_none()
     }
 
-    fn is_correct(&self, real: &Vec<String>) -> bool {
+    pub fn is_correct(&self, real: &[String]) -> bool {
         let Some(out) = &self.out else {
             unreachable!("Attempted to eval an unexecuted program")
         };
@@ -169,10 +191,10 @@ impl ProgramState {
                 return false;
             }
         }
-        return true;
+        true
     }
 
-    fn is_more_optimal(&self, real: usize) -> bool {
+    pub fn is_more_optimal(&self, real: usize) -> bool {
         let Some(out) = &self.out else {
             unreachable!("Attempted to eval an unexecuted program")
         };
@@ -185,35 +207,56 @@ impl ProgramState {
         };
 
         if self.is_more_optimal(real.0) && self.is_correct(&real.1) {
-            return (real.0 as isize) - (out.0 as isize);
+            (real.0 as isize) - (out.0 as isize)
         } else {
-            return -100;
+            -100
         }
     }
 
+    /// The moves offered by default: removing instructions with no observed
+    /// effect and unrolling counting loops.
     pub fn next_moves(&self) -> Vec<Action> {
+        self.moves(&DEFAULT_ACTIONS, 0)
+    }
+
+    /// Every move of the given kinds, with replacements ranging over
+    /// `register_count` registers.
+    pub fn moves(&self, kinds: &[ActionKind], register_count: usize) -> Vec<Action> {
         let mut new_moves = vec![];
+        let cfg = Cfg::new(&self.program);
+
+        if kinds.contains(&ActionKind::Remove) {
+            new_moves.extend((0..self.program.len()).map(Action::Remove));
+        } else if kinds.contains(&ActionKind::RemoveUnused) {
+            // Removing an instruction whose result is observed only keeps the
+            // output intact when the instruction was redundant, which dataflow
+            // finds far cheaper than search, so don't offer it.
+            let used = observably_used(&self.program, &cfg);
+            new_moves.extend(
+                (0..self.program.len())
+                    .filter(|idx| !used[*idx])
+                    .map(Action::Remove)
+                    .collect::<Vec<Action>>(),
+            );
+        }
 
-        new_moves.extend(
-            (0..self.program.len())
-                .map(|idx| Action::Remove(idx))
-                .collect::<Vec<Action>>(),
-        );
         // Replacements
-        // new_moves.extend(
-        //     (0..self.program.len())
-        //         .flat_map(|idx| {
-        //             self.program
-        //                 .get(idx)
-        //                 .unwrap()
-        //                 .code()
-        //                 .instruction_replacements()
-        //                 .into_iter()
-        //                 .map(|rep| Action::Replace(idx, rep))
-        //                 .collect::<Vec<Action>>()
-        //         })
-        //         .collect::<Vec<Action>>(),
-        // );
+        if kinds.contains(&ActionKind::Replace) {
+            new_moves.extend(
+                (0..self.program.len())
+                    .flat_map(|idx| {
+                        self.program
+                            .get(idx)
+                            .unwrap()
+                            .code()
+                            .instruction_replacements(register_count)
+                            .into_iter()
+                            .filter(move |rep| *rep != self.program.get(idx).unwrap().code())
+                            .map(move |rep| Action::Replace(idx, rep))
+                    })
+                    .collect::<Vec<Action>>(),
+            );
+        }
 
         // Additions
         //
@@ -267,34 +310,134 @@ impl ProgramState {
         //         .collect::<Vec<Action>>(),
         // );
 
+        // Unrolling, completely when the copies stay small, and behind a
+        // guard when the trip count is unknown and there's a spare register
+        if kinds.contains(&ActionKind::Unroll) {
+            let spare = spare_register(&self.program) < register_count;
+            for found in counting_loops(&self.program, &cfg) {
+                let Some(trips) = found.trip_count else {
+                    if spare && found.condition_step.is_some() {
+                        new_moves.extend(UNROLL_FACTORS.map(|x| Action::Unroll(found.start, x)));
+                    }
+                    continue;
+                };
+                new_moves.extend(UNROLL_FACTORS.map(|x| Action::Unroll(found.start, x)));
+                if (found.end - found.start - 1) as u64 * trips <= FULL_UNROLL_SIZE {
+                    new_moves.push(Action::Unroll(found.start, usize::MAX));
+                }
+            }
+        }
+
         new_moves
     }
 }
 
+/// Instructions a move may introduce: anything over the VM's registers and
+/// the variables and constants already in the program.
+pub(crate) fn alphabet(program: &Program, register_count: usize) -> Vec<Instruction> {
+    let mut variables = BTreeSet::new();
+    let mut constants = BTreeSet::from([0, 1]);
+    for instruction in program.code() {
+        variables.extend(instruction.reads_variable());
+        variables.extend(instruction.writes_variable());
+        if let Instruction::SetReg { constant, .. } = instruction {
+            constants.insert(constant);
+        }
+    }
+
+    let mut alphabet = vec![];
+    for rega in 0..register_count {
+        for regb in 0..register_count {
+            for outreg in 0..register_count {
+                alphabet.push(Instruction::Add { rega, regb, outreg });
+                alphabet.push(Instruction::Sub { rega, regb, outreg });
+            }
+        }
+        for constant in &constants {
+            alphabet.push(Instruction::SetReg {
+                register: rega,
+                constant: *constant,
+            });
+        }
+        for variable in &variables {
+            alphabet.push(Instruction::Load {
+                register: rega,
+                variable: *variable,
+            });
+            alphabet.push(Instruction::Store {
+                register: rega,
+                variable: *variable,
+            });
+        }
+    }
+    alphabet.extend(variables.iter().map(|x| Instruction::Var(*x)));
+    alphabet
+}
+
+/// A random move from the full `Action` set, plus whatever unrolling
+/// `moves` offers.
+pub(crate) fn random_action(
+    state: &ProgramState,
+    alphabet: &[Instruction],
+    register_count: usize,
+    rng: &mut impl Rng,
+) -> Action {
+    let len = state.program().len();
+    if len == 0 {
+        return Action::Add(0, *alphabet.choose(rng).unwrap());
+    }
+    let idx = rng.gen_range(0..len);
+    match rng.gen_range(0..6) {
+        0 => Action::Remove(idx),
+        1 => Action::Add(rng.gen_range(0..=len), *alphabet.choose(rng).unwrap()),
+        2 => Action::Replace(idx, *alphabet.choose(rng).unwrap()),
+        3 => Action::Move(idx, rng.gen_range(0..len)),
+        4 => {
+            let operands = state
+                .program()
+                .get(idx)
+                .unwrap()
+                .code()
+                .instruction_replacements(register_count);
+            match operands.choose(rng) {
+                Some(instruction) => Action::Replace(idx, *instruction),
+                None => Action::Remove(idx),
+            }
+        }
+        _ => state
+            .moves(&[ActionKind::Unroll], register_count)
+            .into_iter()
+            .choose(rng)
+            .unwrap_or(Action::Remove(idx)),
+    }
+}
+
+/// How a node's value is summarised from the rewards backpropagated
+/// through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// Average reward, the usual UCT estimate.
+    Mean,
+    /// Best reward seen, which suits a search after a single best program.
+    Max,
+}
+
+/// A position in the search tree. Its statistics live in the transposition
+/// table under the program it reaches, so they are shared with every other
+/// node reaching the same program.
 struct Node {
     action: Action,
-    visits: u32,
-    wins: u32,
-    children: HashMap<Action, Node>,
+    program: Vec<Instruction>,
+    /// In the order the moves were generated, so runs are reproducible.
+    children: Vec<Node>,
 }
 
 impl Node {
-    fn new(action: Action) -> Self {
+    fn new(action: Action, program: Vec<Instruction>) -> Self {
         Self {
             action,
-            visits: 0,
-            wins: 0,
-            children: HashMap::new(),
-        }
-    }
-
-    fn uct_value(&self, parent_visits: u32) -> f64 {
-        if self.visits == 0 {
-            f64::INFINITY
-        } else {
-            let exploitation = self.wins as f64 / self.visits as f64;
-            let exploration = (2.0 * (parent_visits as f64).ln() / self.visits as f64).sqrt();
-            exploitation + exploration
+            program,
+            children: vec![],
         }
     }
 
@@ -303,128 +446,442 @@ impl Node {
     }
 }
 
+fn value(statistics: &NodeStatistics, value: Value) -> f64 {
+    match value {
+        Value::Mean => statistics.total / statistics.visits as f64,
+        Value::Max => statistics.best,
+    }
+}
+
+fn uct_value(statistics: &NodeStatistics, parent_visits: u32, config: &MctsConfig) -> f64 {
+    if statistics.visits == 0 {
+        f64::INFINITY
+    } else {
+        let exploitation = value(statistics, config.value);
+        let exploration =
+            config.exploration * ((parent_visits as f64).ln() / statistics.visits as f64).sqrt();
+        exploitation + exploration
+    }
+}
+
+/// Everything an MCTS run depends on. The same config and program always
+/// give the same result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MctsConfig {
+    pub epochs: usize,
+    /// Random rollouts simulated from each expanded node.
+    pub rollouts: usize,
+    /// Random actions played in each rollout.
+    pub rollout_depth: usize,
+    /// Weight of exploration in UCT.
+    pub exploration: f64,
+    pub value: Value,
+    pub seed: u64,
+    /// Registers replacements range over, the VM's when `None`.
+    pub register_count: Option<usize>,
+    pub actions: Vec<ActionKind>,
+    /// Independent trees searched in parallel, the `i`th seeded with
+    /// `seed + i`. The epochs are split between them, and the best result
+    /// wins.
+    pub threads: usize,
+    /// Epochs between `SearchEvent::Snapshot`s, never when 0.
+    pub snapshot_interval: usize,
+}
+
+impl Default for MctsConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 50_000,
+            rollouts: 1,
+            rollout_depth: 20_000,
+            exploration: std::f64::consts::SQRT_2,
+            value: Value::Mean,
+            seed: 0,
+            register_count: None,
+            actions: DEFAULT_ACTIONS.to_vec(),
+            threads: 1,
+            snapshot_interval: 100,
+        }
+    }
+}
+
+/// Reward in `[0, 1]`: the fraction of the reference cost a saving removes.
+fn normalise(saving: u32, real: &(usize, Vec<String>)) -> f64 {
+    saving as f64 / real.0.max(1) as f64
+}
+
 pub fn mcts(
     program: Program,
     vm: &mut VirtualMachine,
     real: &(usize, Vec<String>),
-    epochs: usize,
-    rollout_count: usize,
+    config: &MctsConfig,
 ) -> Option<(u32, Option<Program>)> {
-    let mut root_program = ProgramState::new(program);
-    root_program.exe(vm).expect("Error in root");
-    let mut root = Node::new(Action::Nothing);
-    let mut best_run = u32::MIN;
-    let mut best_out: Option<(u32, Option<Program>)> = None;
-    for epoch in 1..epochs {
-        let run = mcts_node(&mut root, vm, real, &root_program, vec![], rollout_count);
-        println!("Epoch: {}, Op amount {}", epoch, best_run);
-        if run.0 > best_run {
-            best_run = run.0;
-            best_out = Some(run);
-        }
-        // if epoch % 100 == 0 {
-        // }
-    }
-    best_out
+    let budget = Budget::iterations(config.epochs);
+    mcts_with_budget(program, vm, real, config, budget, &NoopReporter).best
 }
 
-fn mcts_node(
-    node: &mut Node,
+/// What one MCTS search found and spent.
+#[derive(Debug, Clone)]
+pub struct MctsRun {
+    pub best: Option<(u32, Option<Program>)>,
+    /// Epochs searched, summed over the trees when there are several.
+    pub epochs: usize,
+    pub cache: CacheStatistics,
+}
+
+/// Like `mcts`, but searches until `budget` is spent instead of for
+/// `config.epochs`, telling `observer` how it goes. The whole budget is
+/// shared by every tree: a limit on iterations is split into equal shares of
+/// epochs, and time, executions and steps are spent by whichever tree gets
+/// to them.
+pub fn mcts_with_budget(
+    program: Program,
     vm: &mut VirtualMachine,
     real: &(usize, Vec<String>),
-    base_state: &ProgramState,
-    mut action_chain: Vec<Action>,
-    rollout_count: usize,
-) -> (u32, Option<Program>) {
-    let better = if node.leaf() {
-        // Expand, simulate
-        // if node.state.is_none() {
-        //     node.state = Some(base_state.applying(&action_chain));
-        // }
-        let node_state = base_state.applying(&action_chain); // can now be unwraped
-
-        let new_states = node_state.next_moves();
-        for new_state in new_states {
-            if !node.children.contains_key(&new_state) {
-                node.children
-                    .insert(new_state.clone(), Node::new(new_state));
-            }
+    config: &MctsConfig,
+    budget: Budget,
+    observer: &dyn SearchObserver,
+) -> MctsRun {
+    let meter = Meter::new(budget);
+    let mut seen = (vm.executions(), vm.steps());
+    let mut root_program = ProgramState::new(program);
+    root_program.exe(vm).expect("Error in root");
+    meter.charge((vm.executions(), vm.steps()), &mut seen);
+    let threads = config.threads.max(1);
+    let total = meter.iterations();
+    let share = |tree: usize| {
+        if total == usize::MAX {
+            total
+        } else {
+            total / threads + usize::from(tree < total % threads)
         }
-        let random_action = node.children.iter_mut().choose(&mut thread_rng()).unwrap();
-        let reward = mcts_simulate(
-            random_action.1,
+    };
+    if threads == 1 {
+        return search_tree(
+            &root_program,
             vm,
             real,
-            base_state,
-            action_chain,
-            rollout_count,
+            config,
+            (0, total),
+            &meter,
+            observer,
         );
-        (reward.0.max(0) as u32, reward.1)
-    } else {
-        let best_child = node
-            .children
-            .iter_mut()
-            .max_by(|(_, child1), (_, child2)| {
-                child1
-                    .uct_value(node.visits)
-                    .partial_cmp(&child2.uct_value(node.visits))
-                    .unwrap()
-            })
-            .unwrap()
-            .1;
+    }
 
-        action_chain.push(best_child.action);
-        mcts_node(
-            best_child,
-            vm,
-            real,
-            base_state,
-            action_chain,
-            rollout_count,
-        )
+    let runs: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|tree| {
+                let mut vm = vm.clone();
+                let (root_program, meter) = (&root_program, &meter);
+                let epochs = share(tree);
+                scope.spawn(move || {
+                    search_tree(
+                        root_program,
+                        &mut vm,
+                        real,
+                        config,
+                        (tree, epochs),
+                        meter,
+                        observer,
+                    )
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|x| x.join().expect("Search thread panicked"))
+            .collect()
+    });
+    // Ties go to the lowest thread, so results don't depend on scheduling
+    let mut merged = MctsRun {
+        best: None,
+        epochs: 0,
+        cache: CacheStatistics::default(),
     };
-    node.visits += 1;
-    node.wins = if better.0 >= 1 { 1 } else { 0 };
-
-    better
+    for run in runs {
+        merged.epochs += run.epochs;
+        merged.cache = merged.cache + run.cache;
+        if let Some(best) = run.best {
+            if merged.best.as_ref().is_none_or(|x| best.0 > x.0) {
+                merged.best = Some(best);
+            }
+        }
+    }
+    merged
 }
 
-fn mcts_simulate(
-    node: &mut Node,
+/// The `tree`th tree searched from `root_program` for at most `max_epochs`,
+/// with its own transposition table.
+fn search_tree(
+    root_program: &ProgramState,
     vm: &mut VirtualMachine,
     real: &(usize, Vec<String>),
-    base_state: &ProgramState,
-    action_chain: Vec<Action>,
-    rollout_count: usize,
-) -> (isize, Option<Program>) {
-    // if node.state.is_none() {
-    //     node.state = Some(base_state.applying(&action_chain));
-    // }
-    let mut rollout_state = base_state.applying(&action_chain);
-    let mut max_reward = isize::MIN;
-
-    let mut max_program: Option<Program> = None;
-    for _ in 0..rollout_count {
-        let next_states = rollout_state.next_moves();
-        if next_states.is_empty() {
+    config: &MctsConfig,
+    (tree, max_epochs): (usize, usize),
+    meter: &Meter,
+    observer: &dyn SearchObserver,
+) -> MctsRun {
+    let start = Instant::now();
+    let mut rng = StdRng::seed_from_u64(config.seed.wrapping_add(tree as u64));
+    let register_count = config.register_count.unwrap_or(vm.register_count());
+    let mut root = Node::new(Action::Nothing, root_program.program.code());
+    let mut best_run = u32::MIN;
+    let mut best_out: Option<(u32, Option<Program>)> = None;
+    let mut search = Search {
+        seen: (vm.executions(), vm.steps()),
+        vm,
+        real,
+        base_state: root_program,
+        config,
+        register_count,
+        rng: &mut rng,
+        table: TranspositionTable::new(),
+        meter,
+    };
+    let mut epochs = 0;
+    while epochs < max_epochs && !meter.stopped() {
+        epochs += 1;
+        let (saving, found) = search.node(&mut root, vec![]);
+        search.charge();
+        if saving > best_run {
+            best_run = saving;
+            if let Some((program, actions)) = &found {
+                observer.notify(&SearchEvent::NewBest {
+                    tree,
+                    epoch: epochs,
+                    saving,
+                    program,
+                    actions,
+                });
+            }
+            best_out = Some((saving, found.map(|(program, _)| program)));
+        }
+        observer.notify(&SearchEvent::EpochFinished {
+            tree,
+            epoch: epochs,
+            best_saving: best_run,
+        });
+        if config.snapshot_interval > 0 && epochs % config.snapshot_interval == 0 {
+            observer.notify(&SearchEvent::Snapshot {
+                tree,
+                epoch: epochs,
+                best_saving: best_run,
+                elapsed: start.elapsed(),
+                cache: search.table.statistics(),
+            });
+        }
+        // Nothing can be done to the program
+        if root.leaf() {
             break;
         }
+    }
+    MctsRun {
+        best: best_out,
+        epochs,
+        cache: search.table.statistics(),
+    }
+}
+
+/// Monte Carlo tree search over `Action` chains, where each expansion is
+/// followed by random rollouts. Rewards are the fraction of the reference
+/// cost saved, 0 for anything incorrect or no cheaper, and are
+/// backpropagated to every node on the path. Children are picked by UCT over
+/// each node's `value`. With more than one of `config.threads`, that many
+/// trees are searched at once. The budget replaces `config.epochs`. Progress
+/// goes to `observer`, a `ConsoleReporter` by default.
+#[derive(Clone)]
+pub struct Mcts {
+    pub config: MctsConfig,
+    pub observer: Arc<dyn SearchObserver>,
+}
 
-        let next_state = next_states.choose(&mut thread_rng()).unwrap().clone();
+impl Default for Mcts {
+    fn default() -> Self {
+        Self::new(MctsConfig::default())
+    }
+}
 
-        rollout_state = rollout_state.applying(&vec![next_state]);
+impl std::fmt::Debug for Mcts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mcts")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
 
-        if rollout_state.exe(vm).is_err() {
-            continue;
+impl Mcts {
+    pub fn new(config: MctsConfig) -> Self {
+        Self {
+            config,
+            observer: Arc::new(ConsoleReporter),
         }
-        let new_r = rollout_state.reward(real);
-        if new_r > max_reward && new_r > 0 {
-            max_reward = new_r;
-            max_program = Some(rollout_state.program.clone());
-        } else if new_r > max_reward {
-            max_reward = new_r
+    }
+
+    pub fn with_observer(mut self, observer: impl SearchObserver + 'static) -> Self {
+        self.observer = Arc::new(observer);
+        self
+    }
+}
+
+impl Optimizer for Mcts {
+    fn name(&self) -> &'static str {
+        "mcts"
+    }
+
+    fn optimize(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+    ) -> Optimized {
+        let start = Instant::now();
+        let run = mcts_with_budget(
+            program.clone(),
+            vm,
+            reference,
+            &self.config,
+            budget,
+            self.observer.as_ref(),
+        );
+        // Only hand back a program that still prints what the input did
+        let found = match run.best {
+            Some((_, Some(found))) => vm
+                .exe(&found)
+                .ok()
+                .filter(|(_, output)| *output == reference.1)
+                .map(|(cost, _)| (found, cost)),
+            _ => None,
+        };
+        let (program, best_cost) = found.unwrap_or_else(|| (program.clone(), reference.0));
+        Optimized {
+            program,
+            statistics: Statistics {
+                iterations: run.epochs,
+                original_cost: reference.0,
+                best_cost,
+                elapsed: start.elapsed(),
+                cache: Some(run.cache),
+            },
         }
     }
+}
 
-    (max_reward, max_program)
+/// A program a search found, with the actions that lead to it from the root.
+type Found = Option<(Program, Vec<Action>)>;
+
+/// State shared by every step of one search.
+struct Search<'a> {
+    vm: &'a mut VirtualMachine,
+    real: &'a (usize, Vec<String>),
+    base_state: &'a ProgramState,
+    config: &'a MctsConfig,
+    register_count: usize,
+    rng: &'a mut StdRng,
+    table: TranspositionTable,
+    meter: &'a Meter,
+    /// Work done when `meter` was last charged.
+    seen: (usize, usize),
+}
+
+impl Search<'_> {
+    /// Charges the meter for the executions and steps of every program
+    /// evaluated, whether the VM or the transposition table answered.
+    fn charge(&mut self) {
+        let saved = self.table.saved();
+        let work = (self.vm.executions() + saved.0, self.vm.steps() + saved.1);
+        self.meter.charge(work, &mut self.seen);
+    }
+
+    fn node(&mut self, node: &mut Node, mut action_chain: Vec<Action>) -> (u32, Found) {
+        let better = if node.leaf() {
+            // Expand, simulate
+            let node_state = self.base_state.applying(&action_chain);
+
+            let mut seen = HashSet::new();
+            for new_state in node_state.moves(&self.config.actions, self.register_count) {
+                if seen.insert(new_state) {
+                    let program = node_state.applying(&vec![new_state]).program().code();
+                    node.children.push(Node::new(new_state, program));
+                }
+            }
+            // Nothing left to try from here
+            match node.children.choose(self.rng) {
+                Some(child) => {
+                    action_chain.push(child.action);
+                    let mut better = (0, None);
+                    for _ in 0..self.config.rollouts.max(1) {
+                        let reward = self.simulate(&action_chain);
+                        let saving = reward.0.max(0) as u32;
+                        self.table
+                            .update(&child.program, normalise(saving, self.real));
+                        if saving > better.0 {
+                            better = (saving, reward.1);
+                        }
+                    }
+                    better
+                }
+                None => (0, None),
+            }
+        } else {
+            let visits = self.table.node(&node.program).visits;
+            let uct =
+                |child: &Node| uct_value(&self.table.node(&child.program), visits, self.config);
+            let best_child = node
+                .children
+                .iter_mut()
+                .max_by(|child1, child2| uct(child1).partial_cmp(&uct(child2)).unwrap())
+                .unwrap();
+
+            action_chain.push(best_child.action);
+            self.node(best_child, action_chain)
+        };
+        self.table
+            .update(&node.program, normalise(better.0, self.real));
+
+        better
+    }
+
+    /// Plays random moves from the end of `action_chain`, returning the best
+    /// reward of any program on the way, the starting one included.
+    fn simulate(&mut self, action_chain: &Vec<Action>) -> (isize, Found) {
+        let mut rollout_state = self.base_state.applying(action_chain);
+        let mut chain = action_chain.clone();
+        let mut max_reward = isize::MIN;
+
+        let mut max_program: Found = None;
+        for step in 0..=self.config.rollout_depth {
+            if step > 0 {
+                // Long rollouts would overrun the budget by a whole epoch
+                self.charge();
+                if self.meter.stopped() {
+                    break;
+                }
+                let next_states = rollout_state.moves(&self.config.actions, self.register_count);
+                if next_states.is_empty() {
+                    break;
+                }
+
+                let next_state = *next_states.choose(self.rng).unwrap();
+
+                rollout_state = rollout_state.applying(&vec![next_state]);
+                chain.push(next_state);
+            }
+
+            let Some(new_r) = self
+                .table
+                .reward(self.vm, rollout_state.program(), self.real)
+            else {
+                continue;
+            };
+            if new_r > max_reward && new_r > 0 {
+                max_reward = new_r;
+                max_program = Some((rollout_state.program.clone(), chain.clone()));
+            } else if new_r > max_reward {
+                max_reward = new_r
+            }
+        }
+
+        (max_reward, max_program)
+    }
 }
@@ -0,0 +1,255 @@
+use crate::annealing::Annealing;
+use crate::beam::Beam;
+use crate::genetic::Genetic;
+use crate::op_finder::Mcts;
+use crate::Program;
+use crate::VirtualMachine;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How much searching a strategy may do. The search stops at whichever
+/// limit it reaches first, or once it is cancelled, and returns the best
+/// program found so far.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    /// Iterations of the strategy's main loop, an MCTS epoch for example.
+    pub iterations: usize,
+    pub time: Option<Duration>,
+    /// Programs run on the VM. Runs a cache answers still count, so a
+    /// search over programs it has all seen still ends.
+    pub executions: Option<usize>,
+    /// Instructions executed by the VM over all runs, counted the same way.
+    pub steps: Option<usize>,
+    pub cancellation: Option<Cancellation>,
+}
+
+impl Budget {
+    pub fn iterations(iterations: usize) -> Self {
+        Self {
+            iterations,
+            time: None,
+            executions: None,
+            steps: None,
+            cancellation: None,
+        }
+    }
+
+    pub fn time(time: Duration) -> Self {
+        Self::iterations(usize::MAX).with_time(time)
+    }
+
+    pub fn executions(executions: usize) -> Self {
+        Self::iterations(usize::MAX).with_executions(executions)
+    }
+
+    pub fn steps(steps: usize) -> Self {
+        Self::iterations(usize::MAX).with_steps(steps)
+    }
+
+    pub fn with_time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn with_executions(mut self, executions: usize) -> Self {
+        self.executions = Some(executions);
+        self
+    }
+
+    pub fn with_steps(mut self, steps: usize) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+}
+
+/// Lets a caller stop a search early, from another thread for example.
+/// Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a search has spent of its `Budget`. It can be shared between the
+/// threads of one search, which each `charge` it for their own work.
+#[derive(Debug)]
+pub struct Meter {
+    budget: Budget,
+    start: Instant,
+    executions: AtomicUsize,
+    steps: AtomicUsize,
+}
+
+impl Meter {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            start: Instant::now(),
+            executions: AtomicUsize::new(0),
+            steps: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds the executions and steps in `work` since `seen`, the totals
+    /// when last charged, and updates `seen`.
+    pub fn charge(&self, work: (usize, usize), seen: &mut (usize, usize)) {
+        self.executions
+            .fetch_add(work.0 - seen.0, Ordering::Relaxed);
+        self.steps.fetch_add(work.1 - seen.1, Ordering::Relaxed);
+        *seen = work;
+    }
+
+    /// Iterations the budget allows.
+    pub fn iterations(&self) -> usize {
+        self.budget.iterations
+    }
+
+    /// Whether a search that has done `iterations` must stop.
+    pub fn exhausted(&self, iterations: usize) -> bool {
+        iterations >= self.budget.iterations || self.stopped()
+    }
+
+    /// Whether the search must stop whatever its iterations, so it can give
+    /// up in the middle of one.
+    pub fn stopped(&self) -> bool {
+        let budget = &self.budget;
+        budget.time.is_some_and(|x| self.start.elapsed() >= x)
+            || budget
+                .executions
+                .is_some_and(|x| self.executions.load(Ordering::Relaxed) >= x)
+            || budget
+                .steps
+                .is_some_and(|x| self.steps.load(Ordering::Relaxed) >= x)
+            || budget
+                .cancellation
+                .as_ref()
+                .is_some_and(|x| x.is_cancelled())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statistics {
+    pub iterations: usize,
+    /// Executed cost of the reference behaviour.
+    pub original_cost: usize,
+    pub best_cost: usize,
+    pub elapsed: Duration,
+    /// How well the strategy's execution cache did, if it has one.
+    pub cache: Option<CacheStatistics>,
+}
+
+impl Statistics {
+    pub fn saving(&self) -> usize {
+        self.original_cost.saturating_sub(self.best_cost)
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cost: {} -> {} in {} iterations ({:.2?})",
+            self.original_cost, self.best_cost, self.iterations, self.elapsed
+        )?;
+        if let Some(cache) = &self.cache {
+            write!(f, ", {cache}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStatistics {
+    /// Executions answered from the cache.
+    pub hits: usize,
+    /// Programs that had to be executed.
+    pub misses: usize,
+    /// Distinct programs the search tree reached.
+    pub programs: usize,
+}
+
+impl CacheStatistics {
+    pub fn hit_rate(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
+
+/// Totals over several caches, such as one per search thread.
+impl std::ops::Add for CacheStatistics {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            programs: self.programs + other.programs,
+        }
+    }
+}
+
+impl Display for CacheStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cache hit rate {:.1}% ({} hits, {} misses, {} programs)",
+            self.hit_rate() * 100.0,
+            self.hits,
+            self.misses,
+            self.programs
+        )
+    }
+}
+
+/// The best program a search found, which is the input when it found
+/// nothing better.
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    pub program: Program,
+    pub statistics: Statistics,
+}
+
+/// A search strategy. Given a program and the cost and output it has on
+/// `vm`, it looks for a cheaper program with the same output.
+pub trait Optimizer {
+    fn name(&self) -> &'static str;
+
+    fn optimize(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+        reference: &(usize, Vec<String>),
+        budget: Budget,
+    ) -> Optimized;
+}
+
+/// Every strategy with its default settings, for picking one by name.
+pub fn strategies() -> Vec<Box<dyn Optimizer>> {
+    vec![
+        Box::new(Mcts::default()),
+        Box::new(Annealing::default()),
+        Box::new(Genetic::default()),
+        Box::new(Beam::default()),
+    ]
+}
+
+pub fn by_name(name: &str) -> Option<Box<dyn Optimizer>> {
+    strategies().into_iter().find(|x| x.name() == name)
+}
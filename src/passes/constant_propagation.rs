@@ -0,0 +1,263 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{solve, Analysis, Direction};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::BTreeMap;
+
+/// Known register and variable values at a program point.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Constants {
+    // Registers missing from the map still hold their initial 0, `None`
+    // marks an unknown value.
+    registers: BTreeMap<usize, Option<i32>>,
+    // Only variables with a known value are present; anything else may come
+    // from the VM's base memory.
+    variables: BTreeMap<usize, i32>,
+}
+
+impl Constants {
+    pub fn register(&self, register: usize) -> Option<i32> {
+        self.registers.get(&register).copied().unwrap_or(Some(0))
+    }
+
+    pub fn variable(&self, variable: usize) -> Option<i32> {
+        self.variables.get(&variable).copied()
+    }
+
+    /// Register values the instruction produces, `None` where unknown.
+    /// Arithmetic that would overflow is unknown, since it errors at runtime.
+    pub fn evaluate(&self, instruction: &Instruction) -> Vec<(usize, Option<i32>)> {
+        let both = |a: usize, b: usize, op: fn(i32, i32) -> Option<i32>| match (
+            self.register(a),
+            self.register(b),
+        ) {
+            (Some(a), Some(b)) => op(a, b),
+            _ => None,
+        };
+        match *instruction {
+            Instruction::Add { rega, regb, outreg } => {
+                vec![(outreg, both(rega, regb, i32::checked_add))]
+            }
+            Instruction::Sub { rega, regb, outreg } => {
+                vec![(outreg, both(rega, regb, i32::checked_sub))]
+            }
+            Instruction::Load { register, variable } => vec![(register, self.variable(variable))],
+            Instruction::SetReg { register, constant } => vec![(register, Some(constant))],
+            // The VM subtracts pairwise despite the name
+            Instruction::VecAdd {
+                a1r,
+                b1r,
+                r1,
+                a2r,
+                b2r,
+                r2,
+            } => vec![
+                (r1, both(a1r, b1r, i32::checked_sub)),
+                (r2, both(a2r, b2r, i32::checked_sub)),
+            ],
+            Instruction::Var(_)
+            | Instruction::Store { .. }
+            | Instruction::PCSetIfNotZero { .. }
+            | Instruction::Output(_) => vec![],
+        }
+    }
+
+    pub fn step(&mut self, instruction: &Instruction) {
+        for (register, value) in self.evaluate(instruction) {
+            self.registers.insert(register, value);
+        }
+        match *instruction {
+            Instruction::Var(variable) => {
+                self.variables.insert(variable, 0);
+            }
+            Instruction::Store { register, variable } => match self.register(register) {
+                Some(value) => {
+                    self.variables.insert(variable, value);
+                }
+                None => {
+                    self.variables.remove(&variable);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn merge(&mut self, other: &Constants) {
+        let registers: Vec<usize> = self
+            .registers
+            .keys()
+            .chain(other.registers.keys())
+            .copied()
+            .collect();
+        for register in registers {
+            let (a, b) = (self.register(register), other.register(register));
+            self.registers
+                .insert(register, if a == b { a } else { None });
+        }
+        self.variables
+            .retain(|variable, value| other.variables.get(variable) == Some(value));
+    }
+}
+
+/// Forward must-analysis of constant values. `None` marks points not yet
+/// (or never) reached.
+pub struct ConstantAnalysis;
+
+impl Analysis for ConstantAnalysis {
+    type Fact = Option<Constants>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        Some(Constants::default())
+    }
+
+    fn initial(&self) -> Self::Fact {
+        None
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        match (into.as_mut(), other) {
+            (_, None) => {}
+            (None, Some(other)) => *into = Some(other.clone()),
+            (Some(into), Some(other)) => into.merge(other),
+        }
+    }
+
+    fn transfer(&self, _index: usize, instruction: &Instruction, fact: &mut Self::Fact) {
+        if let Some(constants) = fact {
+            constants.step(instruction);
+        }
+    }
+}
+
+/// Folds instructions with known results into `SetReg`, and deletes writes
+/// of a value the destination already holds and branches that never jump.
+pub struct ConstantPropagation;
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "constant-propagation"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let facts = solve(&ConstantAnalysis, program, &cfg);
+
+        program.rewrite(|idx, instruction| {
+            let Some(known) = facts.before(idx) else {
+                return vec![*instruction];
+            };
+            let code = instruction.code();
+            match code {
+                Instruction::Add { .. }
+                | Instruction::Sub { .. }
+                | Instruction::Load { .. }
+                | Instruction::SetReg { .. } => {
+                    let (register, value) = known.evaluate(&code)[0];
+                    match value {
+                        Some(value) if known.register(register) == Some(value) => vec![],
+                        Some(value) if !matches!(code, Instruction::SetReg { .. }) => {
+                            vec![InstructionContainer::new(Instruction::SetReg {
+                                register,
+                                constant: value,
+                            })]
+                        }
+                        _ => vec![*instruction],
+                    }
+                }
+                Instruction::VecAdd { .. } => {
+                    let unchanged = known
+                        .evaluate(&code)
+                        .iter()
+                        .all(|(r, v)| v.is_some() && known.register(*r) == *v);
+                    if unchanged {
+                        vec![]
+                    } else {
+                        vec![*instruction]
+                    }
+                }
+                Instruction::Store { register, variable } => {
+                    let value = known.register(register);
+                    if value.is_some() && known.variable(variable) == value {
+                        vec![]
+                    } else {
+                        vec![*instruction]
+                    }
+                }
+                Instruction::PCSetIfNotZero { register, .. } => {
+                    if known.register(register) == Some(0) {
+                        vec![]
+                    } else {
+                        vec![*instruction]
+                    }
+                }
+                Instruction::Var(_) | Instruction::Output(_) => vec![*instruction],
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+
+    #[test]
+    fn folds_known_arithmetic() {
+        let program = Program::new(vec![set(0, 2), set(1, 3), add(0, 1, 2), out(2)]);
+        let folded = ConstantPropagation.run(&program);
+        assert_eq!(folded.code()[2], set(2, 5));
+        assert_same_output(&program, &folded, 3);
+    }
+
+    #[test]
+    fn folds_loads_of_known_variables() {
+        let program = Program::new(vec![var(0), set(0, 4), store(0, 0), load(1, 0), out(1)]);
+        let folded = ConstantPropagation.run(&program);
+        assert!(folded.code().contains(&set(1, 4)));
+        assert!(!folded.code().contains(&load(1, 0)));
+        assert_same_output(&program, &folded, 2);
+    }
+
+    #[test]
+    fn leaves_overflowing_arithmetic() {
+        let program = Program::new(vec![set(0, i32::MAX), set(1, 1), add(0, 1, 0), out(0)]);
+        assert_eq!(ConstantPropagation.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn removes_branches_that_never_jump_and_fixes_up_jumps() {
+        let program = Program::new(vec![
+            set(0, 0),
+            jnz(0, 5),
+            set(1, 3),
+            set(2, 1),
+            sub(1, 2, 1),
+            jnz(1, 4),
+            out(1),
+        ]);
+        let folded = ConstantPropagation.run(&program);
+        assert!(!folded.code().contains(&jnz(0, 5)));
+        let (before, after) = assert_same_output(&program, &folded, 3);
+        assert!(after < before);
+    }
+
+    #[test]
+    fn keeps_loop_counters() {
+        let program = count_to_x::prog(10);
+        assert_same_output(&program, &ConstantPropagation.run(&program), 2);
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            assert_same_output_on(&mut vm, &program, &ConstantPropagation.run(&program));
+        }
+    }
+}
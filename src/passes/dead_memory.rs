@@ -0,0 +1,101 @@
+use crate::cfg::Cfg;
+use crate::dataflow::variable_liveness;
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::Program;
+
+/// Deletes `Store`s whose value is never loaded, then `Var`s of variables
+/// that are never loaded or stored to again. Memory is not part of the
+/// output, so only loads observe it.
+pub struct DeadMemoryElimination;
+
+impl Pass for DeadMemoryElimination {
+    fn name(&self) -> &'static str {
+        "dead-memory"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let live = variable_liveness(program, &cfg);
+        let stores = program.rewrite(|idx, instruction| match instruction.code() {
+            Instruction::Store { variable, .. } if !live.after(idx).contains(&variable) => {
+                vec![]
+            }
+            _ => vec![*instruction],
+        });
+
+        // A declaration is still needed by any later store to the variable
+        let cfg = Cfg::new(&stores);
+        let live = variable_liveness(&stores, &cfg);
+        let stored: Vec<usize> = stores
+            .iter()
+            .filter_map(|x| match x.code() {
+                Instruction::Store { variable, .. } => Some(variable),
+                _ => None,
+            })
+            .collect();
+        stores.rewrite(|idx, instruction| match instruction.code() {
+            Instruction::Var(variable)
+                if !live.after(idx).contains(&variable) && !stored.contains(&variable) =>
+            {
+                vec![]
+            }
+            _ => vec![*instruction],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn removes_stores_never_loaded() {
+        let program = Program::new(vec![var(0), set(0, 1), store(0, 0), out(0)]);
+        let cleaned = DeadMemoryElimination.run(&program);
+        assert_eq!(cleaned.code(), vec![set(0, 1), out(0)]);
+        assert_same_output(&program, &cleaned, 1);
+    }
+
+    #[test]
+    fn keeps_declarations_of_stored_variables() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 1),
+            store(0, 0),
+            load(1, 0),
+            store(1, 0),
+            out(1),
+        ]);
+        let cleaned = DeadMemoryElimination.run(&program);
+        assert!(cleaned.code().contains(&var(0)));
+        assert_same_output(&program, &cleaned, 2);
+    }
+
+    #[test]
+    fn fixes_up_jumps_over_removed_stores() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 2),
+            set(1, 1),
+            store(0, 0),
+            sub(0, 1, 0),
+            jnz(0, 3),
+            out(0),
+        ]);
+        let cleaned = DeadMemoryElimination.run(&program);
+        assert_eq!(
+            cleaned.code(),
+            vec![set(0, 2), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)]
+        );
+        assert_same_output(&program, &cleaned, 2);
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            assert_same_output_on(&mut vm, &program, &DeadMemoryElimination.run(&program));
+        }
+    }
+}
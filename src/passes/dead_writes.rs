@@ -0,0 +1,104 @@
+use crate::cfg::Cfg;
+use crate::dataflow::register_liveness;
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::Program;
+
+/// Deletes instructions whose only effect is writing registers that are
+/// never read afterwards.
+pub struct DeadRegisterWrites;
+
+impl Pass for DeadRegisterWrites {
+    fn name(&self) -> &'static str {
+        "dead-register-writes"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let mut current = program.clone();
+        loop {
+            let cfg = Cfg::new(&current);
+            let live = register_liveness(&current, &cfg);
+            let mut changed = false;
+            let next = current.rewrite(|idx, instruction| {
+                let code = instruction.code();
+                let register_only = matches!(
+                    code,
+                    Instruction::Add { .. }
+                        | Instruction::Sub { .. }
+                        | Instruction::Load { .. }
+                        | Instruction::SetReg { .. }
+                        | Instruction::VecAdd { .. }
+                );
+                if register_only && code.defs().iter().all(|r| !live.after(idx).contains(r)) {
+                    changed = true;
+                    vec![]
+                } else {
+                    vec![*instruction]
+                }
+            });
+            if !changed {
+                return current;
+            }
+            current = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::{add_two, count_to_x};
+    use crate::testing::*;
+
+    #[test]
+    fn removes_overwritten_writes() {
+        let program = Program::new(vec![set(0, 1), set(1, 2), set(0, 3), out(0)]);
+        let cleaned = DeadRegisterWrites.run(&program);
+        assert_eq!(cleaned.code(), vec![set(0, 3), out(0)]);
+        assert_same_output(&program, &cleaned, 2);
+    }
+
+    #[test]
+    fn removes_chains_of_dead_writes() {
+        let program = Program::new(vec![
+            set(0, 1),
+            add(0, 0, 1),
+            add(1, 1, 2),
+            set(3, 1),
+            out(3),
+        ]);
+        let cleaned = DeadRegisterWrites.run(&program);
+        assert_eq!(cleaned.code(), vec![set(3, 1), out(3)]);
+    }
+
+    #[test]
+    fn keeps_writes_read_around_a_loop() {
+        let program = count_to_x::prog(5);
+        assert_eq!(DeadRegisterWrites.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn fixes_up_jumps_over_removed_writes() {
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            set(2, 9),
+            sub(0, 1, 0),
+            set(3, 4),
+            jnz(0, 3),
+            out(0),
+        ]);
+        let cleaned = DeadRegisterWrites.run(&program);
+        assert_eq!(
+            cleaned.code(),
+            vec![set(0, 3), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)]
+        );
+        assert_same_output(&program, &cleaned, 4);
+    }
+
+    #[test]
+    fn keeps_the_output_of_add_two() {
+        let program = add_two::prog();
+        assert_same_output(&program, &DeadRegisterWrites.run(&program), 4);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{register_liveness, Solution};
+use crate::loops::{natural_loops, NaturalLoop};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Hoists loop invariant `SetReg`s and `Load`s into a preheader in front of
+/// the loop header. An instruction whose register is the only one of its
+/// kind in the loop is moved as is. One whose register gets reused in the
+/// loop is moved into a register the program never touches, as long as its
+/// value is consumed within its own block.
+pub struct LoopInvariantCodeMotion {
+    register_count: usize,
+}
+
+impl LoopInvariantCodeMotion {
+    pub fn new(register_count: usize) -> Self {
+        Self { register_count }
+    }
+}
+
+struct Hoisted {
+    preheader: Vec<Instruction>,
+    removed: BTreeSet<usize>,
+    rewritten: BTreeMap<usize, Instruction>,
+}
+
+impl Pass for LoopInvariantCodeMotion {
+    fn name(&self) -> &'static str {
+        "loop-invariant-code-motion"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        for natural in natural_loops(&cfg) {
+            if let Some(hoisted) = self.hoist(program, &cfg, &natural) {
+                return apply(program, &cfg, &natural, hoisted);
+            }
+        }
+        program.clone()
+    }
+}
+
+impl LoopInvariantCodeMotion {
+    fn hoist(&self, program: &Program, cfg: &Cfg, natural: &NaturalLoop) -> Option<Hoisted> {
+        let header = cfg.block(natural.header);
+        if natural.entries(cfg).is_empty() && natural.header != 0 {
+            return None;
+        }
+        // A preheader can't go between a block and the header it falls into
+        if natural
+            .body
+            .iter()
+            .any(|x| cfg.block(*x).end == header.start)
+        {
+            return None;
+        }
+
+        let code = program.code();
+        let live = register_liveness(program, cfg);
+        let dominators = cfg.dominators();
+        let inside: Vec<usize> = natural
+            .body
+            .iter()
+            .flat_map(|x| cfg.block(*x).range())
+            .collect();
+        let exiting: Vec<usize> = natural
+            .body
+            .iter()
+            .copied()
+            .filter(|x| {
+                let block = cfg.block(*x);
+                block.exits || block.successors.iter().any(|x| !natural.body.contains(x))
+            })
+            .collect();
+        let written: BTreeSet<usize> = inside
+            .iter()
+            .filter_map(|x| code[*x].writes_variable())
+            .collect();
+        let mut definitions: BTreeMap<usize, usize> = BTreeMap::new();
+        for idx in &inside {
+            for register in code[*idx].defs() {
+                *definitions.entry(register).or_insert(0) += 1;
+            }
+        }
+        let mut free = (0..self.register_count).filter(|r| {
+            !code
+                .iter()
+                .any(|x| x.uses().contains(r) || x.defs().contains(r))
+        });
+
+        let mut hoisted = Hoisted {
+            preheader: vec![],
+            removed: BTreeSet::new(),
+            rewritten: BTreeMap::new(),
+        };
+        // Hoisted values already given their own register
+        let mut renamed: HashMap<Instruction, usize> = HashMap::new();
+
+        for &idx in &inside {
+            let register = match code[idx] {
+                Instruction::SetReg { register, .. } => register,
+                Instruction::Load { register, variable } => {
+                    // Loads can fail, so only hoist ones that run every
+                    // iteration and before the loop can be left
+                    let block = cfg.block_of(idx);
+                    let always_run = natural
+                        .latches
+                        .iter()
+                        .chain(&exiting)
+                        .all(|x| dominators[*x].contains(&block));
+                    if written.contains(&variable) || !always_run {
+                        continue;
+                    }
+                    register
+                }
+                _ => continue,
+            };
+
+            if definitions[&register] == 1 && !live.before(header.start).contains(&register) {
+                hoisted.preheader.push(code[idx]);
+                hoisted.removed.insert(idx);
+                continue;
+            }
+
+            let Some(uses) = uses_in_block(&code, cfg, &live, idx, register) else {
+                continue;
+            };
+            let canonical = code[idx].map_registers(|_| 0);
+            let target = match renamed.get(&canonical) {
+                Some(target) => *target,
+                None => {
+                    let Some(target) = free.next() else {
+                        continue;
+                    };
+                    renamed.insert(canonical, target);
+                    hoisted.preheader.push(code[idx].map_registers(|_| target));
+                    target
+                }
+            };
+            hoisted.removed.insert(idx);
+            for used in uses {
+                let current = hoisted.rewritten.get(&used).copied().unwrap_or(code[used]);
+                hoisted.rewritten.insert(
+                    used,
+                    current.map_uses(|r| if r == register { target } else { r }),
+                );
+            }
+        }
+
+        if hoisted.preheader.is_empty() {
+            None
+        } else {
+            Some(hoisted)
+        }
+    }
+}
+
+/// Instructions after `idx` in its block that read the value it writes, as
+/// long as nothing outside the block can see that value.
+fn uses_in_block(
+    code: &[Instruction],
+    cfg: &Cfg,
+    live: &Solution<BTreeSet<usize>>,
+    idx: usize,
+    register: usize,
+) -> Option<Vec<usize>> {
+    let block = cfg.block(cfg.block_of(idx));
+    let mut uses = vec![];
+    for (next, instruction) in code.iter().enumerate().take(block.end).skip(idx + 1) {
+        if instruction.uses().contains(&register) {
+            // Output names the register it prints
+            if matches!(instruction, Instruction::Output(_)) {
+                return None;
+            }
+            uses.push(next);
+        }
+        if instruction.defs().contains(&register) {
+            return Some(uses);
+        }
+    }
+    if live.after(block.end - 1).contains(&register) {
+        None
+    } else {
+        Some(uses)
+    }
+}
+
+fn apply(program: &Program, cfg: &Cfg, natural: &NaturalLoop, hoisted: Hoisted) -> Program {
+    let header = cfg.block(natural.header).start;
+    let inside: BTreeSet<usize> = natural
+        .body
+        .iter()
+        .flat_map(|x| cfg.block(*x).range())
+        .collect();
+
+    let (expanded, starts) = program.expand(|idx, instruction| {
+        let mut out = vec![];
+        if idx == header {
+            out.extend(
+                hoisted
+                    .preheader
+                    .iter()
+                    .map(|x| InstructionContainer::new(*x)),
+            );
+        }
+        if let Some(rewritten) = hoisted.rewritten.get(&idx) {
+            out.push(InstructionContainer::new(*rewritten));
+        } else if !hoisted.removed.contains(&idx) {
+            out.push(*instruction);
+        }
+        out
+    });
+
+    // Entries now run through the preheader, back edges skip it
+    let len = program.len();
+    expanded.retarget(|branch, target| {
+        let owner = starts.partition_point(|x| *x <= branch) - 1;
+        if target == header && inside.contains(&owner) {
+            starts[header] + hoisted.preheader.len()
+        } else {
+            starts[target.min(len)]
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::PassManager;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn hoists_invariant_loads() {
+        // Counts r0 down from 3, reloading the step every iteration
+        let program = Program::new(vec![
+            var(0),
+            set(1, 1),
+            store(1, 0),
+            set(0, 3),
+            load(1, 0),
+            sub(0, 1, 0),
+            jnz(0, 4),
+            out(0),
+        ]);
+        let hoisted = LoopInvariantCodeMotion::new(4).run(&program);
+        assert_eq!(
+            hoisted.code(),
+            vec![
+                var(0),
+                set(1, 1),
+                store(1, 0),
+                set(0, 3),
+                load(1, 0),
+                sub(0, 1, 0),
+                jnz(0, 5),
+                out(0),
+            ]
+        );
+        let (before, after) = assert_same_output(&program, &hoisted, 4);
+        assert!(after < before);
+    }
+
+    #[test]
+    fn keeps_loads_of_variables_the_loop_writes() {
+        let program = Program::new(vec![
+            var(0),
+            set(1, 1),
+            store(1, 0),
+            set(0, 3),
+            load(1, 0),
+            sub(0, 1, 0),
+            store(1, 0),
+            jnz(0, 4),
+            out(0),
+        ]);
+        let hoisted = LoopInvariantCodeMotion::new(4).run(&program);
+        assert_eq!(hoisted.code(), program.code());
+    }
+
+    #[test]
+    fn keeps_loads_the_loop_can_exit_before() {
+        // Leaves from the header straight away, so the load never runs
+        let program = Program::new(vec![
+            set(1, -1),
+            set(2, -1),
+            jnz(2, 10),
+            load(3, 0),
+            jnz(3, 2),
+            out(0),
+            jnz(1, 4),
+            load(2, 0),
+            store(3, 0),
+            out(0),
+            set(3, 2),
+        ]);
+        let hoisted = LoopInvariantCodeMotion::new(4).run(&program);
+        assert_same_output(&program, &hoisted, 4);
+        let report = PassManager::classic(4)
+            .run_checked(&program, &mut VirtualMachine::new(4))
+            .unwrap();
+        assert_same_output(&program, &report.program, 4);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::cfg::Cfg;
+use crate::loops::counting_loops;
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+
+/// Replaces counting loops with known inputs by `SetReg`s of the values
+/// their registers hold on exit.
+pub struct LoopClosedForm;
+
+impl Pass for LoopClosedForm {
+    fn name(&self) -> &'static str {
+        "loop-closed-form"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let evaluated: Vec<_> = counting_loops(program, &cfg)
+            .into_iter()
+            .filter(|x| x.final_values.is_some())
+            .collect();
+
+        program.rewrite(|idx, instruction| {
+            let Some(found) = evaluated.iter().find(|x| (x.start..x.end).contains(&idx)) else {
+                return vec![*instruction];
+            };
+            if idx != found.start {
+                return vec![];
+            }
+            found
+                .final_values
+                .iter()
+                .flatten()
+                .map(|(register, constant)| {
+                    InstructionContainer::new(Instruction::SetReg {
+                        register: *register,
+                        constant: *constant,
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+
+    #[test]
+    fn collapses_count_to_x() {
+        let program = count_to_x::prog(10);
+        let folded = LoopClosedForm.run(&program);
+        assert_eq!(
+            folded.code(),
+            vec![var(0), set(0, 0), set(0, 10), set(1, 0), out(0)]
+        );
+        let (before, after) = assert_same_output(&program, &folded, 4);
+        assert!(after < before);
+    }
+
+    #[test]
+    fn keeps_loops_that_overflow() {
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            set(2, 1_000_000_000),
+            set(3, 0),
+            sub(0, 1, 0),
+            add(3, 2, 3),
+            jnz(0, 4),
+            out(3),
+        ]);
+        assert_eq!(LoopClosedForm.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn keeps_loops_that_never_end() {
+        let program = Program::new(vec![set(0, 3), set(1, 2), sub(0, 1, 0), jnz(0, 2), out(0)]);
+        assert_eq!(LoopClosedForm.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn keeps_loops_over_memory() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 3),
+            set(1, 1),
+            sub(0, 1, 0),
+            store(0, 0),
+            jnz(0, 3),
+            out(0),
+        ]);
+        assert_eq!(LoopClosedForm.run(&program).code(), program.code());
+    }
+}
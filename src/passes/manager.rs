@@ -0,0 +1,342 @@
+use crate::passes::{
+    ConstantPropagation, DeadMemoryElimination, DeadRegisterWrites, LocalValueNumbering,
+    LoopClosedForm, LoopInvariantCodeMotion, MemoryToRegister, Pass, Peephole, RedundantLoads,
+};
+use crate::vm::ExecutionError;
+use crate::Instruction;
+use crate::Program;
+use crate::VirtualMachine;
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum VerificationError {
+    /// A jump point left the program it pointed into.
+    JumpOutOfRange { pass: &'static str, index: usize },
+    /// An operand names a register the VM doesn't have.
+    RegisterOutOfRange { pass: &'static str, index: usize },
+    /// The transformed program failed where the original ran.
+    Execution {
+        pass: &'static str,
+        error: ExecutionError,
+    },
+    /// The transformed program printed something else.
+    OutputChanged {
+        pass: &'static str,
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct PassStatistics {
+    pub name: &'static str,
+    pub runs: usize,
+    /// Runs that changed the program.
+    pub changes: usize,
+    /// Drop in `Program::cost`, the static sum of instruction costs.
+    pub static_saving: isize,
+    /// Drop in the cost the VM reports, when runs were checked by execution.
+    pub executed_saving: Option<isize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PassReport {
+    pub program: Program,
+    pub iterations: usize,
+    pub reached_fixpoint: bool,
+    pub passes: Vec<PassStatistics>,
+}
+
+impl PassReport {
+    pub fn static_saving(&self) -> isize {
+        self.passes.iter().map(|x| x.static_saving).sum()
+    }
+
+    pub fn executed_saving(&self) -> Option<isize> {
+        self.passes.iter().map(|x| x.executed_saving).sum()
+    }
+}
+
+impl Display for PassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} iteration(s){}",
+            self.iterations,
+            if self.reached_fixpoint {
+                ", fixpoint reached"
+            } else {
+                ""
+            }
+        )?;
+        for pass in &self.passes {
+            write!(
+                f,
+                "{}: {} run(s), {} change(s), static cost {:+}",
+                pass.name, pass.runs, pass.changes, -pass.static_saving
+            )?;
+            if let Some(saving) = pass.executed_saving {
+                write!(f, ", executed cost {:+}", -saving)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a sequence of passes repeatedly until none of them changes the
+/// program, verifying the result of every pass.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    max_iterations: usize,
+}
+
+impl PassManager {
+    pub fn new(passes: Vec<Box<dyn Pass>>) -> Self {
+        Self {
+            passes,
+            max_iterations: 32,
+        }
+    }
+
+    /// Every built-in pass, in the order below, for a VM with
+    /// `register_count` registers.
+    pub fn classic(register_count: usize) -> Self {
+        Self::new(vec![
+            Box::new(ConstantPropagation),
+            Box::new(Peephole::builtin()),
+            Box::new(LoopClosedForm),
+            Box::new(LoopInvariantCodeMotion::new(register_count)),
+            Box::new(LocalValueNumbering),
+            Box::new(RedundantLoads),
+            Box::new(DeadMemoryElimination),
+            Box::new(MemoryToRegister::new(register_count)),
+            Box::new(DeadRegisterWrites),
+        ])
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn push(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass)
+    }
+
+    /// Runs the pipeline, only checking each result is well formed.
+    pub fn run(&self, program: &Program) -> Result<PassReport, VerificationError> {
+        self.optimise(program, None)
+    }
+
+    /// Runs the pipeline, also executing each result on `vm` and rejecting
+    /// it unless it prints what `program` prints. Programs that fail on `vm`
+    /// to begin with are only checked for well formedness.
+    pub fn run_checked(
+        &self,
+        program: &Program,
+        vm: &mut VirtualMachine,
+    ) -> Result<PassReport, VerificationError> {
+        self.optimise(program, Some(vm))
+    }
+
+    fn optimise(
+        &self,
+        program: &Program,
+        mut vm: Option<&mut VirtualMachine>,
+    ) -> Result<PassReport, VerificationError> {
+        let reference = vm.as_mut().and_then(|vm| vm.exe(program).ok());
+        let mut passes: Vec<PassStatistics> = self
+            .passes
+            .iter()
+            .map(|x| PassStatistics {
+                name: x.name(),
+                runs: 0,
+                changes: 0,
+                static_saving: 0,
+                executed_saving: reference.as_ref().map(|_| 0),
+            })
+            .collect();
+
+        let mut current = program.clone();
+        let mut executed_cost = reference.as_ref().map(|x| x.0);
+        let mut iterations = 0;
+        let mut reached_fixpoint = false;
+        while iterations < self.max_iterations {
+            iterations += 1;
+            let mut changed = false;
+            for (pass, stats) in self.passes.iter().zip(passes.iter_mut()) {
+                let next = pass.run(&current);
+                stats.runs += 1;
+                if next.code() == current.code() {
+                    continue;
+                }
+                verify(pass.name(), &next, vm.as_deref())?;
+                if let (Some(vm), Some((_, expected))) = (vm.as_mut(), &reference) {
+                    let cost = check_output(pass.name(), &next, vm, expected)?;
+                    if let (Some(saving), Some(before)) =
+                        (stats.executed_saving.as_mut(), executed_cost)
+                    {
+                        *saving += before as isize - cost as isize;
+                    }
+                    executed_cost = Some(cost);
+                }
+                stats.changes += 1;
+                stats.static_saving += current.cost() as isize - next.cost() as isize;
+                current = next;
+                changed = true;
+            }
+            if !changed {
+                reached_fixpoint = true;
+                break;
+            }
+        }
+
+        Ok(PassReport {
+            program: current,
+            iterations,
+            reached_fixpoint,
+            passes,
+        })
+    }
+}
+
+fn verify(
+    pass: &'static str,
+    program: &Program,
+    vm: Option<&VirtualMachine>,
+) -> Result<(), VerificationError> {
+    for (index, instruction) in program.iter().enumerate() {
+        let code = instruction.code();
+        if let Instruction::PCSetIfNotZero { jump_point, .. } = code {
+            if jump_point > program.len() {
+                return Err(VerificationError::JumpOutOfRange { pass, index });
+            }
+        }
+        if let Some(vm) = vm {
+            let registers = code.uses().into_iter().chain(code.defs());
+            if registers.into_iter().any(|r| r >= vm.register_count()) {
+                return Err(VerificationError::RegisterOutOfRange { pass, index });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_output(
+    pass: &'static str,
+    program: &Program,
+    vm: &mut VirtualMachine,
+    expected: &[String],
+) -> Result<usize, VerificationError> {
+    match vm.exe(program) {
+        Ok((cost, found)) if found == expected => Ok(cost),
+        Ok((_, found)) => Err(VerificationError::OutputChanged {
+            pass,
+            expected: expected.to_vec(),
+            found,
+        }),
+        Err(error) => Err(VerificationError::Execution { pass, error }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+
+    /// Prints register 1 instead of register 0.
+    struct WrongOutput;
+
+    impl Pass for WrongOutput {
+        fn name(&self) -> &'static str {
+            "wrong-output"
+        }
+
+        fn run(&self, program: &Program) -> Program {
+            program.rewrite(|_, x| match x.code() {
+                Instruction::Output(0) => vec![crate::InstructionContainer::new(out(1))],
+                _ => vec![*x],
+            })
+        }
+    }
+
+    /// Points every jump past the end of the program.
+    struct WildJumps;
+
+    impl Pass for WildJumps {
+        fn name(&self) -> &'static str {
+            "wild-jumps"
+        }
+
+        fn run(&self, program: &Program) -> Program {
+            program.clone().retarget(|_, _| 100)
+        }
+    }
+
+    #[test]
+    fn classic_pipeline_keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            let report = PassManager::classic(4)
+                .run_checked(&program, &mut vm)
+                .unwrap();
+            assert!(report.reached_fixpoint);
+            let (before, after) = assert_same_output_on(&mut vm, &program, &report.program);
+            assert_eq!(
+                report.executed_saving(),
+                Some(before as isize - after as isize)
+            );
+        }
+    }
+
+    #[test]
+    fn stops_at_a_fixpoint() {
+        let program = Program::new(vec![set(0, 1), set(0, 2), out(0)]);
+        let report = PassManager::new(vec![Box::new(DeadRegisterWrites)])
+            .run(&program)
+            .unwrap();
+        assert_eq!(report.iterations, 2);
+        assert!(report.reached_fixpoint);
+        assert_eq!(report.passes[0].runs, 2);
+        assert_eq!(report.passes[0].changes, 1);
+        assert_eq!(report.static_saving(), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_iterations() {
+        let program = Program::new(vec![set(0, 1), set(0, 2), out(0)]);
+        let report = PassManager::new(vec![Box::new(DeadRegisterWrites)])
+            .with_max_iterations(1)
+            .run(&program)
+            .unwrap();
+        assert!(!report.reached_fixpoint);
+    }
+
+    #[test]
+    fn rejects_a_pass_that_changes_the_output() {
+        let program = Program::new(vec![set(0, 1), set(1, 2), out(0)]);
+        let mut vm = VirtualMachine::new(2);
+        let result = PassManager::new(vec![Box::new(WrongOutput)]).run_checked(&program, &mut vm);
+        assert!(matches!(
+            result,
+            Err(VerificationError::OutputChanged {
+                pass: "wrong-output",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_the_program() {
+        let program = count_to_x::prog(3);
+        let result = PassManager::new(vec![Box::new(WildJumps)]).run(&program);
+        assert!(matches!(
+            result,
+            Err(VerificationError::JumpOutOfRange {
+                pass: "wild-jumps",
+                index: 6
+            })
+        ));
+    }
+}
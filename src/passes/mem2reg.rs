@@ -0,0 +1,240 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{solve, Analysis, Direction};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Forward must-analysis of the variables declared on every path. `None` is
+/// the unreached top element.
+struct Declared;
+
+impl Analysis for Declared {
+    type Fact = Option<BTreeSet<usize>>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        Some(BTreeSet::new())
+    }
+
+    fn initial(&self) -> Self::Fact {
+        None
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        match (into.as_mut(), other) {
+            (_, None) => {}
+            (None, Some(other)) => *into = Some(other.clone()),
+            (Some(into), Some(other)) => into.retain(|x| other.contains(x)),
+        }
+    }
+
+    fn transfer(&self, _index: usize, instruction: &Instruction, fact: &mut Self::Fact) {
+        if let (Some(declared), Instruction::Var(variable)) = (fact, instruction) {
+            declared.insert(*variable);
+        }
+    }
+}
+
+/// Promotes variables into registers the program never touches. `Var`
+/// becomes a `SetReg` of 0 and `Load`/`Store` become copies, an `Add` of a
+/// register that is never written and so always holds 0. Only variables
+/// declared on every path to each of their accesses are promoted, since any
+/// other access reads the VM's base memory or fails. The most accessed
+/// variables go first when there aren't enough spare registers.
+pub struct MemoryToRegister {
+    register_count: usize,
+}
+
+impl MemoryToRegister {
+    pub fn new(register_count: usize) -> Self {
+        Self { register_count }
+    }
+}
+
+impl Pass for MemoryToRegister {
+    fn name(&self) -> &'static str {
+        "mem2reg"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let declared = solve(&Declared, program, &cfg);
+        let code = program.code();
+
+        let mut accesses: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut unsafe_variables = BTreeSet::new();
+        for (idx, instruction) in code.iter().enumerate() {
+            let variable = match *instruction {
+                Instruction::Load { variable, .. } | Instruction::Store { variable, .. } => {
+                    variable
+                }
+                _ => continue,
+            };
+            *accesses.entry(variable).or_insert(0) += 1;
+            if !declared
+                .before(idx)
+                .as_ref()
+                .is_none_or(|x| x.contains(&variable))
+            {
+                unsafe_variables.insert(variable);
+            }
+        }
+        let mut candidates: Vec<(usize, usize)> = accesses
+            .into_iter()
+            .filter(|(variable, _)| !unsafe_variables.contains(variable))
+            .collect();
+        if candidates.is_empty() {
+            return program.clone();
+        }
+        candidates.sort_by_key(|(variable, count)| (std::cmp::Reverse(*count), *variable));
+
+        let touched = |r: &usize| {
+            code.iter()
+                .any(|x| x.uses().contains(r) || x.defs().contains(r))
+        };
+        let mut free = (0..self.register_count).filter(|r| !touched(r));
+        let zero =
+            match (0..self.register_count).find(|r| !code.iter().any(|x| x.defs().contains(r))) {
+                Some(zero) => zero,
+                None => match free.next() {
+                    Some(zero) => zero,
+                    None => return program.clone(),
+                },
+            };
+        let promoted: BTreeMap<usize, usize> = candidates
+            .into_iter()
+            .map(|(variable, _)| variable)
+            .zip(free.filter(|r| *r != zero))
+            .collect();
+        if promoted.is_empty() {
+            return program.clone();
+        }
+
+        let copy = |from: usize, to: usize| Instruction::Add {
+            rega: from,
+            regb: zero,
+            outreg: to,
+        };
+        program.rewrite(|_, instruction| {
+            let replaced = match instruction.code() {
+                Instruction::Var(variable) => {
+                    promoted.get(&variable).map(|x| Instruction::SetReg {
+                        register: *x,
+                        constant: 0,
+                    })
+                }
+                Instruction::Load { register, variable } => {
+                    promoted.get(&variable).map(|x| copy(*x, register))
+                }
+                Instruction::Store { register, variable } => {
+                    promoted.get(&variable).map(|x| copy(register, *x))
+                }
+                _ => None,
+            };
+            match replaced {
+                Some(replaced) => vec![InstructionContainer::new(replaced)],
+                None => vec![*instruction],
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+    use std::collections::HashMap;
+
+    fn round_trip() -> Program {
+        Program::new(vec![var(0), set(0, 5), store(0, 0), load(1, 0), out(1)])
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            let promoted = MemoryToRegister::new(4).run(&program);
+            assert_same_output_on(&mut vm, &program, &promoted);
+        }
+    }
+
+    #[test]
+    fn promotes_a_declared_variable() {
+        let program = round_trip();
+        let promoted = MemoryToRegister::new(4).run(&program);
+        assert_eq!(
+            promoted.code(),
+            vec![set(3, 0), set(0, 5), add(0, 2, 3), add(3, 2, 1), out(1)]
+        );
+        assert_same_output(&program, &promoted, 4);
+    }
+
+    #[test]
+    fn needs_a_spare_register_besides_the_zero() {
+        let program = round_trip();
+        assert_eq!(
+            MemoryToRegister::new(3).run(&program).code(),
+            program.code()
+        );
+        assert_eq!(
+            MemoryToRegister::new(2).run(&program).code(),
+            program.code()
+        );
+    }
+
+    #[test]
+    fn promotes_the_most_accessed_variable_first() {
+        let program = Program::new(vec![
+            var(0),
+            var(1),
+            set(0, 5),
+            store(0, 0),
+            store(0, 1),
+            load(1, 1),
+            load(1, 0),
+            load(1, 1),
+            out(1),
+        ]);
+        let promoted = MemoryToRegister::new(4).run(&program);
+        assert!(promoted.code().contains(&load(1, 0)));
+        assert!(!promoted.code().contains(&load(1, 1)));
+        assert_same_output(&program, &promoted, 4);
+    }
+
+    #[test]
+    fn leaves_variables_read_before_they_are_declared() {
+        // The first load reads the VM's base memory
+        let program = Program::new(vec![
+            load(1, 0),
+            out(1),
+            var(0),
+            store(1, 0),
+            load(0, 0),
+            out(0),
+        ]);
+        let promoted = MemoryToRegister::new(4).run(&program);
+        assert_eq!(promoted.code(), program.code());
+        let mut vm = VirtualMachine::from_memory_state(4, HashMap::from([(0, 3)]));
+        assert_same_output_on(&mut vm, &program, &promoted);
+    }
+
+    #[test]
+    fn leaves_variables_declared_on_only_one_path() {
+        let program = Program::new(vec![
+            set(0, 1),
+            jnz(0, 3),
+            var(0),
+            set(1, 2),
+            store(1, 0),
+            load(2, 0),
+            out(2),
+        ]);
+        let promoted = MemoryToRegister::new(4).run(&program);
+        assert_eq!(promoted.code(), program.code());
+    }
+}
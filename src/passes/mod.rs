@@ -0,0 +1,34 @@
+use crate::Program;
+
+pub mod constant_propagation;
+pub mod dead_memory;
+pub mod dead_writes;
+pub mod licm;
+pub mod loop_closed_form;
+pub mod manager;
+pub mod mem2reg;
+pub mod peephole;
+pub mod redundant_loads;
+pub mod unroll;
+pub mod value_numbering;
+
+pub use constant_propagation::ConstantPropagation;
+pub use dead_memory::DeadMemoryElimination;
+pub use dead_writes::DeadRegisterWrites;
+pub use licm::LoopInvariantCodeMotion;
+pub use loop_closed_form::LoopClosedForm;
+pub use manager::{PassManager, PassReport, PassStatistics, VerificationError};
+pub use mem2reg::MemoryToRegister;
+pub use peephole::Peephole;
+pub use redundant_loads::RedundantLoads;
+pub use unroll::LoopUnroll;
+pub use value_numbering::LocalValueNumbering;
+
+/// A deterministic transformation that keeps the program's output intact.
+/// Passes may drop instructions that would have failed at runtime, since a
+/// program that errored had no output worth preserving.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    fn run(&self, program: &Program) -> Program;
+}
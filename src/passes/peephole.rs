@@ -0,0 +1,149 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{register_liveness, solve, Solution};
+use crate::passes::constant_propagation::{ConstantAnalysis, Constants};
+use crate::passes::Pass;
+use crate::rules::{parse_rules, Context, Rule, RuleError};
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+const BUILTIN_RULES: &str = include_str!("../../rules/peephole.rules");
+
+/// What the program looks like around a window starting at `start` and
+/// ending before `end`.
+struct Window<'a> {
+    constants: &'a Solution<Option<Constants>>,
+    live: &'a Solution<BTreeSet<usize>>,
+    start: usize,
+    end: usize,
+}
+
+impl Context for Window<'_> {
+    fn is_zero(&self, register: usize) -> bool {
+        self.constants
+            .before(self.start)
+            .as_ref()
+            .is_some_and(|x| x.register(register) == Some(0))
+    }
+
+    fn is_dead(&self, register: usize) -> bool {
+        !self.live.after(self.end - 1).contains(&register)
+    }
+}
+
+/// Applies rewrite rules read from a rule file (see `rules`) until none of
+/// them matches. Rules are tried in file order at each position, and a match
+/// never spans a jump target, so the matched instructions always run
+/// together. The rules themselves are trusted.
+pub struct Peephole {
+    rules: Vec<Rule>,
+    max_iterations: usize,
+}
+
+impl Peephole {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            max_iterations: 64,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, RuleError> {
+        Ok(Self::new(parse_rules(text)?))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, RuleError> {
+        let text = std::fs::read_to_string(path).map_err(RuleError::Io)?;
+        Self::parse(&text)
+    }
+
+    /// The rules in `rules/peephole.rules`.
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_RULES).expect("Invalid builtin peephole rules")
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Rewrites every non-overlapping match in one sweep over the program,
+    /// `None` if nothing matched.
+    pub fn rewrite_once(&self, program: &Program) -> Option<Program> {
+        let cfg = Cfg::new(program);
+        let constants = solve(&ConstantAnalysis, program, &cfg);
+        let live = register_liveness(program, &cfg);
+        let code = program.code();
+
+        let mut replaced: BTreeMap<usize, Vec<InstructionContainer>> = BTreeMap::new();
+        let mut removed = BTreeSet::new();
+        let mut idx = 0;
+        while idx < code.len() {
+            let found = self.rules.iter().find_map(|rule| {
+                let end = idx + rule.pattern.len();
+                if end > code.len() || (idx + 1..end).any(|x| cfg.block(cfg.block_of(x)).start == x)
+                {
+                    return None;
+                }
+                let window = Window {
+                    constants: &constants,
+                    live: &live,
+                    start: idx,
+                    end,
+                };
+                let replacement = rule.instantiate(&rule.bind(&code[idx..end], &window)?)?;
+                // A rule rewriting something into itself would never finish
+                (replacement != code[idx..end]).then_some((end, replacement))
+            });
+            match found {
+                Some((end, replacement)) => {
+                    replaced.insert(
+                        idx,
+                        replacement
+                            .into_iter()
+                            .map(InstructionContainer::new)
+                            .collect(),
+                    );
+                    removed.extend(idx + 1..end);
+                    idx = end;
+                }
+                None => idx += 1,
+            }
+        }
+
+        if replaced.is_empty() {
+            return None;
+        }
+        Some(program.rewrite(|idx, instruction| {
+            if let Some(replacement) = replaced.get(&idx) {
+                replacement.clone()
+            } else if removed.contains(&idx) {
+                vec![]
+            } else {
+                vec![*instruction]
+            }
+        }))
+    }
+}
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let mut program = program.clone();
+        for _ in 0..self.max_iterations {
+            match self.rewrite_once(&program) {
+                Some(rewritten) => program = rewritten,
+                None => break,
+            }
+        }
+        program
+    }
+}
@@ -0,0 +1,147 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{solve, Analysis, Direction};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::Program;
+use std::collections::BTreeSet;
+
+/// Forward must-analysis of `(register, variable)` pairs known to hold the
+/// same value. `None` is the unreached top element.
+struct MirroredValues;
+
+impl Analysis for MirroredValues {
+    type Fact = Option<BTreeSet<(usize, usize)>>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> Self::Fact {
+        Some(BTreeSet::new())
+    }
+
+    fn initial(&self) -> Self::Fact {
+        None
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        match (into.as_mut(), other) {
+            (_, None) => {}
+            (None, Some(other)) => *into = Some(other.clone()),
+            (Some(into), Some(other)) => into.retain(|pair| other.contains(pair)),
+        }
+    }
+
+    fn transfer(&self, _index: usize, instruction: &Instruction, fact: &mut Self::Fact) {
+        let Some(pairs) = fact else {
+            return;
+        };
+        let defs = instruction.defs();
+        pairs.retain(|(r, _)| !defs.contains(r));
+        if let Some(variable) = instruction.writes_variable() {
+            pairs.retain(|(_, v)| *v != variable);
+        }
+        match *instruction {
+            Instruction::Load { register, variable }
+            | Instruction::Store { register, variable } => {
+                pairs.insert((register, variable));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Deletes a `Load` into a register that already holds the variable's value,
+/// typically straight after storing it, and likewise a `Store` of a value the
+/// variable already holds.
+pub struct RedundantLoads;
+
+impl Pass for RedundantLoads {
+    fn name(&self) -> &'static str {
+        "redundant-loads"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let facts = solve(&MirroredValues, program, &cfg);
+        program.rewrite(
+            |idx, instruction| match (instruction.code(), facts.before(idx)) {
+                (
+                    Instruction::Load { register, variable }
+                    | Instruction::Store { register, variable },
+                    Some(pairs),
+                ) if pairs.contains(&(register, variable)) => vec![],
+                _ => vec![*instruction],
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn removes_a_load_of_the_value_just_stored() {
+        let program = Program::new(vec![var(0), set(0, 5), store(0, 0), load(0, 0), out(0)]);
+        let cleaned = RedundantLoads.run(&program);
+        assert_eq!(cleaned.code(), vec![var(0), set(0, 5), store(0, 0), out(0)]);
+        assert_same_output(&program, &cleaned, 1);
+    }
+
+    #[test]
+    fn keeps_loads_after_the_register_changes() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 5),
+            store(0, 0),
+            set(0, 1),
+            load(0, 0),
+            out(0),
+        ]);
+        assert_eq!(RedundantLoads.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn keeps_loads_after_another_store() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 5),
+            set(1, 6),
+            store(0, 0),
+            store(1, 0),
+            load(0, 0),
+            out(0),
+        ]);
+        assert_eq!(RedundantLoads.run(&program).code(), program.code());
+    }
+
+    #[test]
+    fn keeps_loads_a_loop_back_edge_invalidates() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 6),
+            set(1, 1),
+            store(0, 0),
+            load(0, 0),
+            sub(0, 1, 0),
+            store(0, 0),
+            sub(0, 1, 0),
+            jnz(0, 4),
+            out(0),
+        ]);
+        let cleaned = RedundantLoads.run(&program);
+        assert_eq!(cleaned.code(), program.code());
+        assert_same_output(&program, &cleaned, 2);
+    }
+
+    #[test]
+    fn keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            let cleaned = RedundantLoads.run(&program);
+            let (before, after) = assert_same_output_on(&mut vm, &program, &cleaned);
+            assert!(after <= before);
+        }
+    }
+}
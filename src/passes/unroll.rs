@@ -0,0 +1,358 @@
+use crate::cfg::Cfg;
+use crate::loops::{counting_loops, CountingLoop};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+
+/// Unrolls the counting loop starting at `start` by `factor`. The first
+/// `trip_count % factor` iterations are peeled off in front, after which the
+/// exit check only has to run once every `factor` iterations. A factor of at
+/// least the trip count unrolls the loop completely.
+///
+/// Loops with an unknown trip count whose exit condition changes by a fixed
+/// step get a main loop of `factor` copies behind a guard that checks none
+/// of the next `factor - 1` iterations exits, falling back to a remainder of
+/// single iterations otherwise. The guard needs a scratch register, the
+/// lowest one the program doesn't touch, which the VM has to have.
+pub fn unroll(program: &Program, start: usize, factor: usize) -> Option<Program> {
+    let cfg = Cfg::new(program);
+    let found = counting_loops(program, &cfg)
+        .into_iter()
+        .find(|x| x.start == start)?;
+    unroll_loop(program, &found, factor)
+}
+
+/// The lowest register no instruction of `program` reads or writes.
+pub fn spare_register(program: &Program) -> usize {
+    let code = program.code();
+    (0..)
+        .find(|r| {
+            !code
+                .iter()
+                .any(|x| x.uses().contains(r) || x.defs().contains(r))
+        })
+        .unwrap()
+}
+
+fn unroll_loop(program: &Program, found: &CountingLoop, factor: usize) -> Option<Program> {
+    if factor < 2 {
+        return None;
+    }
+    let Some(trips) = found.trip_count else {
+        return unroll_guarded(program, found, factor);
+    };
+    let trips = usize::try_from(trips).ok()?;
+    let body: Vec<InstructionContainer> = (found.start..found.end - 1)
+        .map(|x| *program.get(x).unwrap())
+        .collect();
+    let branch = *program.get(found.end - 1).unwrap();
+    let copies = |count: usize| {
+        (0..count)
+            .flat_map(|_| body.iter().map(|x| InstructionContainer::new(x.code())))
+            .collect::<Vec<InstructionContainer>>()
+    };
+
+    let (peeled, unrolled) = if factor >= trips {
+        (trips, None)
+    } else {
+        (trips % factor, Some(factor))
+    };
+    let prologue = peeled * body.len();
+
+    let (expanded, starts) = program.expand(|idx, instruction| {
+        if idx == found.start {
+            let mut out = copies(peeled);
+            if let Some(factor) = unrolled {
+                out.extend(copies(factor));
+                out.push(branch);
+            }
+            out
+        } else if (found.start..found.end).contains(&idx) {
+            vec![]
+        } else {
+            vec![*instruction]
+        }
+    });
+
+    // The back edge goes to the unrolled body, past the peeled iterations
+    let back_edge = starts[found.start] + prologue;
+    let last = starts[found.start + 1] - 1;
+    let len = program.len();
+    Some(expanded.retarget(|branch, target| {
+        if unrolled.is_some() && branch == last {
+            back_edge
+        } else {
+            starts[target.min(len)]
+        }
+    }))
+}
+
+// Lays the loop out as
+//
+//         SetReg s 1; jnz s START
+//   MAIN: body * (factor - 1)
+//  START: body
+//         for k in (1..factor).rev():
+//             SetReg s k*step; Add c s s; jnz s <next k>
+//             body
+//         jnz c MAIN
+//
+// where `c` is the register the exit condition is in and `step` how much it
+// changes per iteration. After an iteration that doesn't exit, the loop exits
+// after `k` more iterations exactly when `c + k*step` is zero, which only
+// happens for one `k`. The guard for that `k` falls into the remaining
+// iterations, which end with `c` zero and so fall out of the loop; if it is
+// at least `factor` the main loop runs another `factor` iterations.
+fn unroll_guarded(program: &Program, found: &CountingLoop, factor: usize) -> Option<Program> {
+    let step = i128::from(found.condition_step?);
+    let factor_i128 = i128::try_from(factor).ok()?;
+    if (factor_i128 - 1) * step.abs() > i128::from(i32::MAX) {
+        return None;
+    }
+    let Instruction::PCSetIfNotZero {
+        register: condition,
+        ..
+    } = program.get(found.end - 1)?.code()
+    else {
+        return None;
+    };
+    let scratch = spare_register(program);
+    let body: Vec<Instruction> = (found.start..found.end - 1)
+        .map(|x| program.get(x).unwrap().code())
+        .collect();
+
+    // Nothing before the loop moves, so new jumps can be absolute
+    let main = found.start + 2;
+    let first = main + (factor - 1) * body.len();
+    let mut code = vec![
+        Instruction::SetReg {
+            register: scratch,
+            constant: 1,
+        },
+        Instruction::PCSetIfNotZero {
+            register: scratch,
+            jump_point: first,
+        },
+    ];
+    for _ in 0..factor {
+        code.extend(&body);
+    }
+    for k in (1..factor_i128).rev() {
+        let next = found.start + code.len() + 3 + body.len();
+        code.extend([
+            Instruction::SetReg {
+                register: scratch,
+                constant: i32::try_from(k * step).ok()?,
+            },
+            Instruction::Add {
+                rega: condition,
+                regb: scratch,
+                outreg: scratch,
+            },
+            Instruction::PCSetIfNotZero {
+                register: scratch,
+                jump_point: next,
+            },
+        ]);
+        code.extend(&body);
+    }
+    code.push(Instruction::PCSetIfNotZero {
+        register: condition,
+        jump_point: main,
+    });
+
+    let generated = found.start..found.start + code.len();
+    let (expanded, starts) = program.expand(|idx, instruction| {
+        if idx == found.start {
+            code.iter().map(|x| InstructionContainer::new(*x)).collect()
+        } else if (found.start..found.end).contains(&idx) {
+            vec![]
+        } else {
+            vec![*instruction]
+        }
+    });
+    let len = program.len();
+    Some(expanded.retarget(|branch, target| {
+        if generated.contains(&branch) {
+            target
+        } else {
+            starts[target.min(len)]
+        }
+    }))
+}
+
+/// Unrolls counting loops with a known trip count, fully when the copies fit
+/// in `max_size` instructions and otherwise by `factor` as long as the
+/// unrolled loop still fits. Re-running it keeps unrolling until nothing
+/// fits any more. Given the VM's register count it also unrolls loops with an
+/// unknown trip count behind a guard, if there is a spare register for it.
+pub struct LoopUnroll {
+    factor: usize,
+    max_size: usize,
+    register_count: Option<usize>,
+}
+
+impl LoopUnroll {
+    pub fn new(factor: usize) -> Self {
+        Self {
+            factor,
+            max_size: 64,
+            register_count: None,
+        }
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_register_count(mut self, register_count: usize) -> Self {
+        self.register_count = Some(register_count);
+        self
+    }
+}
+
+impl Pass for LoopUnroll {
+    fn name(&self) -> &'static str {
+        "loop-unroll"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        // Only the first loop, since unrolling moves every later one
+        for found in counting_loops(program, &cfg) {
+            let body = (found.end - found.start - 1) as u64;
+            let Some(trips) = found.trip_count else {
+                let spare = self
+                    .register_count
+                    .is_some_and(|x| spare_register(program) < x);
+                // Both the main loop and the guarded iterations hold
+                // `factor` copies
+                let factor = self.factor as u64;
+                let size = body * 2 * factor + 3 * factor;
+                if spare && size <= self.max_size as u64 {
+                    if let Some(unrolled) = unroll_loop(program, &found, self.factor) {
+                        return unrolled;
+                    }
+                }
+                continue;
+            };
+            let factor = if body * trips <= self.max_size as u64 {
+                usize::MAX
+            } else {
+                let factor = self.factor as u64;
+                if factor < 2 || body * (factor + trips % factor) + 1 > self.max_size as u64 {
+                    continue;
+                }
+                self.factor
+            };
+            if let Some(unrolled) = unroll_loop(program, &found, factor) {
+                return unrolled;
+            }
+        }
+        program.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::count_to_x;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+    use std::collections::HashMap;
+
+    // Counts r0 up to a bound loaded from variable 0
+    fn count_to_loaded() -> Program {
+        Program::new(vec![
+            load(2, 0),
+            set(0, 0),
+            set(1, 1),
+            add(0, 1, 0),
+            sub(0, 2, 3),
+            jnz(3, 2),
+            out(0),
+        ])
+    }
+
+    fn bound(n: i32) -> VirtualMachine {
+        VirtualMachine::from_memory_state(5, HashMap::from([(0, n)]))
+    }
+
+    #[test]
+    fn known_trip_count_peels_the_remainder() {
+        let program = count_to_x::prog(10);
+        let start = counting_loops(&program, &Cfg::new(&program))[0].start;
+        for factor in [2, 3, 4, usize::MAX] {
+            let unrolled = unroll(&program, start, factor).unwrap();
+            let (before, after) = assert_same_output(&program, &unrolled, 4);
+            assert!(after < before, "factor {factor}: {before} -> {after}");
+        }
+    }
+
+    #[test]
+    fn unknown_trip_count_gets_a_guarded_main_loop() {
+        let program = count_to_loaded();
+        let found = &counting_loops(&program, &Cfg::new(&program))[0];
+        assert_eq!(found.trip_count, None);
+        assert_eq!(found.condition_step, Some(1));
+        assert_eq!(spare_register(&program), 4);
+
+        for factor in [2, 3, 4] {
+            let unrolled = unroll(&program, found.start, factor).unwrap();
+            // Every bound exercises a different number of remaining
+            // iterations, including exiting after the first one
+            for n in 1..=12 {
+                assert_same_output_on(&mut bound(n), &program, &unrolled);
+            }
+        }
+    }
+
+    #[test]
+    fn guard_handles_negative_steps() {
+        // Counts r0 down from a loaded value to zero
+        let program = Program::new(vec![load(0, 0), set(1, 2), sub(0, 1, 0), jnz(0, 1), out(0)]);
+        let found = &counting_loops(&program, &Cfg::new(&program))[0];
+        assert_eq!(found.condition_step, Some(-2));
+        let unrolled = unroll(&program, found.start, 3).unwrap();
+        for n in (2..=20).step_by(2) {
+            assert_same_output_on(&mut bound(n), &program, &unrolled);
+        }
+    }
+
+    #[test]
+    fn jumps_into_the_loop_are_kept() {
+        let program = Program::new(vec![
+            load(2, 0),
+            set(0, 0),
+            jnz(2, 4),
+            out(2),
+            set(1, 1),
+            add(0, 1, 0),
+            sub(0, 2, 3),
+            jnz(3, 4),
+            out(0),
+        ]);
+        let start = counting_loops(&program, &Cfg::new(&program))[0].start;
+        let unrolled = unroll(&program, start, 2).unwrap();
+        for n in 1..=6 {
+            assert_same_output_on(&mut bound(n), &program, &unrolled);
+        }
+    }
+
+    #[test]
+    fn loop_unroll_needs_a_spare_register_for_unknown_trip_counts() {
+        let program = count_to_loaded();
+        assert_eq!(LoopUnroll::new(2).run(&program), program);
+        assert_eq!(
+            LoopUnroll::new(2).with_register_count(4).run(&program),
+            program
+        );
+        let unrolled = LoopUnroll::new(2).with_register_count(5).run(&program);
+        assert_ne!(unrolled, program);
+        for n in 1..=5 {
+            assert_same_output_on(&mut bound(n), &program, &unrolled);
+        }
+    }
+}
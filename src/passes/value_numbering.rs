@@ -0,0 +1,296 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{register_liveness, Solution};
+use crate::passes::Pass;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Expression {
+    Constant(i32),
+    Add(usize, usize),
+    Sub(usize, usize),
+}
+
+/// Value numbers held by registers and variables within one block. Anything
+/// first read in the block gets a fresh number for its unknown entry value.
+#[derive(Default)]
+struct Numbering {
+    next: usize,
+    registers: HashMap<usize, usize>,
+    variables: HashMap<usize, usize>,
+    expressions: HashMap<Expression, usize>,
+}
+
+impl Numbering {
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+
+    fn register(&mut self, register: usize) -> usize {
+        match self.registers.get(&register) {
+            Some(number) => *number,
+            None => {
+                let number = self.fresh();
+                self.registers.insert(register, number);
+                number
+            }
+        }
+    }
+
+    fn variable(&mut self, variable: usize) -> usize {
+        match self.variables.get(&variable) {
+            Some(number) => *number,
+            None => {
+                let number = self.fresh();
+                self.variables.insert(variable, number);
+                number
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: Expression) -> usize {
+        match self.expressions.get(&expression) {
+            Some(number) => *number,
+            None => {
+                let number = self.fresh();
+                self.expressions.insert(expression, number);
+                number
+            }
+        }
+    }
+
+    fn holder(&self, number: usize) -> Option<usize> {
+        self.registers
+            .iter()
+            .filter(|(_, x)| **x == number)
+            .map(|(r, _)| *r)
+            .min()
+    }
+}
+
+/// Local value numbering. Within each basic block a `Load`, `SetReg`, `Add`
+/// or `Sub` producing a value some register already holds is deleted, its
+/// later uses reading that register instead, and `Store`s of the value a
+/// variable already holds are dropped.
+pub struct LocalValueNumbering;
+
+impl Pass for LocalValueNumbering {
+    fn name(&self) -> &'static str {
+        "local-value-numbering"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let live = register_liveness(program, &cfg);
+        let mut code = program.code();
+        let mut removed = BTreeSet::new();
+        let mut rewritten = BTreeSet::new();
+
+        for block in cfg.blocks() {
+            let mut numbering = Numbering::default();
+            for idx in block.range() {
+                let (register, number) = match code[idx] {
+                    Instruction::SetReg { register, constant } => (
+                        register,
+                        numbering.expression(Expression::Constant(constant)),
+                    ),
+                    Instruction::Add { rega, regb, outreg } => {
+                        let (a, b) = (numbering.register(rega), numbering.register(regb));
+                        let key = Expression::Add(a.min(b), a.max(b));
+                        (outreg, numbering.expression(key))
+                    }
+                    Instruction::Sub { rega, regb, outreg } => {
+                        let (a, b) = (numbering.register(rega), numbering.register(regb));
+                        (outreg, numbering.expression(Expression::Sub(a, b)))
+                    }
+                    Instruction::Load { register, variable } => {
+                        (register, numbering.variable(variable))
+                    }
+                    Instruction::Store { register, variable } => {
+                        let number = numbering.register(register);
+                        if numbering.variables.get(&variable) == Some(&number) {
+                            removed.insert(idx);
+                        } else {
+                            numbering.variables.insert(variable, number);
+                        }
+                        continue;
+                    }
+                    Instruction::Var(variable) => {
+                        let zero = numbering.expression(Expression::Constant(0));
+                        numbering.variables.insert(variable, zero);
+                        continue;
+                    }
+                    Instruction::VecAdd {
+                        a1r,
+                        b1r,
+                        r1,
+                        a2r,
+                        b2r,
+                        r2,
+                    } => {
+                        // The VM subtracts pairwise
+                        let first =
+                            Expression::Sub(numbering.register(a1r), numbering.register(b1r));
+                        let second =
+                            Expression::Sub(numbering.register(a2r), numbering.register(b2r));
+                        let (first, second) =
+                            (numbering.expression(first), numbering.expression(second));
+                        numbering.registers.insert(r1, first);
+                        numbering.registers.insert(r2, second);
+                        continue;
+                    }
+                    Instruction::PCSetIfNotZero { .. } | Instruction::Output(_) => continue,
+                };
+
+                if numbering.registers.get(&register) == Some(&number) {
+                    removed.insert(idx);
+                    continue;
+                }
+                let renamed = numbering
+                    .holder(number)
+                    .and_then(|holder| uses_until_redefined(&code, &live, block.end, idx, holder));
+                match renamed {
+                    Some((holder, uses)) => {
+                        removed.insert(idx);
+                        for used in uses {
+                            code[used] =
+                                code[used].map_uses(|r| if r == register { holder } else { r });
+                            rewritten.insert(used);
+                        }
+                    }
+                    None => {
+                        numbering.registers.insert(register, number);
+                    }
+                }
+            }
+        }
+
+        program.rewrite(|idx, instruction| {
+            if removed.contains(&idx) {
+                vec![]
+            } else if rewritten.contains(&idx) {
+                vec![InstructionContainer::new(code[idx])]
+            } else {
+                vec![*instruction]
+            }
+        })
+    }
+}
+
+/// The instructions reading the register `idx` writes, up to where it is
+/// written again, provided `holder` keeps its value until the last of them
+/// and the register's new value isn't needed past the block.
+fn uses_until_redefined(
+    code: &[Instruction],
+    live: &Solution<BTreeSet<usize>>,
+    end: usize,
+    idx: usize,
+    holder: usize,
+) -> Option<(usize, Vec<usize>)> {
+    let register = code[idx].defs()[0];
+    let mut uses = vec![];
+    let mut clobbered = false;
+    for (next, instruction) in code.iter().enumerate().take(end).skip(idx + 1) {
+        if instruction.uses().contains(&register) {
+            // Output names the register it prints
+            if clobbered || matches!(instruction, Instruction::Output(_)) {
+                return None;
+            }
+            uses.push(next);
+        }
+        if instruction.defs().contains(&register) {
+            return Some((holder, uses));
+        }
+        clobbered |= instruction.defs().contains(&holder);
+    }
+    if live.after(end - 1).contains(&register) {
+        None
+    } else {
+        Some((holder, uses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn keeps_the_output_of_the_examples() {
+        for (program, mut vm) in examples() {
+            let numbered = LocalValueNumbering.run(&program);
+            let (before, after) = assert_same_output_on(&mut vm, &program, &numbered);
+            assert!(after <= before);
+        }
+    }
+
+    #[test]
+    fn removes_a_repeated_computation() {
+        let program = Program::new(vec![
+            set(0, 2),
+            set(1, 3),
+            add(0, 1, 2),
+            add(1, 0, 3),
+            add(2, 3, 0),
+            out(0),
+        ]);
+        let numbered = LocalValueNumbering.run(&program);
+        assert_eq!(
+            numbered.code(),
+            vec![set(0, 2), set(1, 3), add(0, 1, 2), add(2, 2, 0), out(0)]
+        );
+        assert_same_output(&program, &numbered, 4);
+    }
+
+    #[test]
+    fn keeps_a_computation_whose_operand_changed() {
+        let program = Program::new(vec![
+            set(0, 2),
+            set(1, 3),
+            add(0, 1, 2),
+            set(0, 7),
+            add(0, 1, 3),
+            add(2, 3, 0),
+            out(0),
+        ]);
+        let numbered = LocalValueNumbering.run(&program);
+        assert_eq!(numbered.code(), program.code());
+    }
+
+    #[test]
+    fn keeps_a_computation_whose_holder_is_overwritten() {
+        let program = Program::new(vec![
+            set(0, 2),
+            set(1, 3),
+            add(0, 1, 2),
+            add(0, 1, 3),
+            set(2, 1),
+            add(2, 3, 0),
+            out(0),
+        ]);
+        let numbered = LocalValueNumbering.run(&program);
+        assert_eq!(numbered.code(), program.code());
+    }
+
+    #[test]
+    fn reuses_stored_values() {
+        let program = Program::new(vec![
+            var(0),
+            set(0, 4),
+            store(0, 0),
+            load(1, 0),
+            store(1, 0),
+            add(1, 1, 2),
+            out(2),
+        ]);
+        let numbered = LocalValueNumbering.run(&program);
+        assert_eq!(
+            numbered.code(),
+            vec![var(0), set(0, 4), store(0, 0), add(0, 0, 2), out(2)]
+        );
+        assert_same_output(&program, &numbered, 4);
+    }
+}
@@ -2,7 +2,7 @@ use crate::Instruction;
 use crate::InstructionContainer;
 use std::fmt::Display;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Program(Vec<InstructionContainer>);
 
 impl Display for Program {
@@ -14,12 +14,11 @@ impl Display for Program {
 
 impl Program {
     pub fn new(start: Vec<Instruction>) -> Self {
-        Program(
-            start
-                .into_iter()
-                .map(|x| InstructionContainer::new(x))
-                .collect(),
-        )
+        Program(start.into_iter().map(InstructionContainer::new).collect())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InstructionContainer> {
+        self.0.iter()
     }
 
     pub fn get(&self, index: usize) -> Option<&InstructionContainer> {
@@ -30,6 +29,19 @@ impl Program {
         self.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn code(&self) -> Vec<Instruction> {
+        self.0.iter().map(|x| x.code()).collect()
+    }
+
+    /// Sum of the instruction costs, ignoring how often each one runs.
+    pub fn cost(&self) -> usize {
+        self.0.iter().map(|x| x.cost()).sum()
+    }
+
     pub fn insert(&mut self, index: usize, element: InstructionContainer) {
         self.0.insert(index, element)
     }
@@ -37,4 +49,109 @@ impl Program {
     pub fn remove(&mut self, index: usize) -> Instruction {
         self.0.remove(index).code()
     }
+
+    /// Builds a new program by replacing each instruction with the sequence
+    /// `f` returns for it (empty to delete it). Jump points in the returned
+    /// instructions refer to the old indices and are remapped, a jump to a
+    /// deleted instruction landing on whatever follows it.
+    pub fn rewrite(
+        &self,
+        f: impl FnMut(usize, &InstructionContainer) -> Vec<InstructionContainer>,
+    ) -> Program {
+        let (program, starts) = self.expand(f);
+        program.retarget(|_, target| starts[target.min(self.0.len())])
+    }
+
+    /// Like `rewrite`, but leaves jump points alone and instead returns the
+    /// index each old instruction's replacement starts at, plus one past the
+    /// end.
+    pub fn expand(
+        &self,
+        mut f: impl FnMut(usize, &InstructionContainer) -> Vec<InstructionContainer>,
+    ) -> (Program, Vec<usize>) {
+        let mut starts = Vec::with_capacity(self.0.len() + 1);
+        let mut out: Vec<InstructionContainer> = vec![];
+        for (idx, instruction) in self.0.iter().enumerate() {
+            starts.push(out.len());
+            out.extend(f(idx, instruction));
+        }
+        starts.push(out.len());
+        (Program(out), starts)
+    }
+
+    /// Moves every jump point through `f`, given the index of the branch and
+    /// its current target.
+    pub fn retarget(mut self, f: impl Fn(usize, usize) -> usize) -> Program {
+        for (idx, instruction) in self.0.iter_mut().enumerate() {
+            if let Instruction::PCSetIfNotZero {
+                register,
+                jump_point,
+            } = instruction.code()
+            {
+                let target = f(idx, jump_point);
+                if target != jump_point {
+                    *instruction = InstructionContainer::new(Instruction::PCSetIfNotZero {
+                        register,
+                        jump_point: target,
+                    });
+                }
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn rewrite_remaps_jumps_across_removed_instructions() {
+        let program = Program::new(vec![
+            set(0, 3),
+            set(1, 1),
+            set(2, 7),
+            sub(0, 1, 0),
+            jnz(0, 2),
+            out(0),
+        ]);
+        let rewritten = program.rewrite(|idx, x| if idx == 0 { vec![] } else { vec![*x] });
+        assert_eq!(rewritten.code()[3], jnz(0, 1));
+    }
+
+    #[test]
+    fn rewrite_lands_jumps_to_a_removed_instruction_on_the_next() {
+        let program = Program::new(vec![
+            set(0, 2),
+            set(1, 1),
+            set(2, 7),
+            sub(0, 1, 0),
+            jnz(0, 2),
+        ]);
+        let rewritten = program.rewrite(|idx, x| if idx == 2 { vec![] } else { vec![*x] });
+        assert_eq!(rewritten.code()[3], jnz(0, 2));
+        assert_eq!(rewritten.code()[2], sub(0, 1, 0));
+    }
+
+    #[test]
+    fn rewrite_shifts_jumps_past_inserted_instructions() {
+        let program = Program::new(vec![set(0, 2), set(1, 1), sub(0, 1, 0), jnz(0, 2), out(0)]);
+        let rewritten = program.rewrite(|idx, x| {
+            if idx == 1 {
+                vec![InstructionContainer::new(set(2, 5)), *x]
+            } else {
+                vec![*x]
+            }
+        });
+        assert_eq!(rewritten.code()[4], jnz(0, 3));
+        assert_same_output(&program, &rewritten, 3);
+    }
+
+    #[test]
+    fn rewrite_keeps_jumps_to_the_end() {
+        let program = Program::new(vec![set(0, 1), jnz(0, 3), out(0)]);
+        let rewritten = program.rewrite(|idx, x| if idx == 2 { vec![] } else { vec![*x] });
+        assert_eq!(rewritten.code()[1], jnz(0, 2));
+    }
 }
@@ -0,0 +1,411 @@
+use crate::cfg::Cfg;
+use crate::dataflow::register_liveness;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug)]
+pub enum AllocationError {
+    /// Some instruction needs more registers at once than the VM has, even
+    /// with everything else spilled.
+    TooFewRegisters,
+}
+
+/// Registers whose values are live at the same time, and so can't share a
+/// physical register.
+#[derive(Debug, Clone, Default)]
+pub struct InterferenceGraph {
+    edges: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+impl InterferenceGraph {
+    pub fn new(program: &Program, cfg: &Cfg) -> Self {
+        Self::with_copies(program, cfg, &BTreeSet::new())
+    }
+
+    /// Like `new`, but the instructions at `copies` are `Add`s of a register
+    /// holding 0, so the destination can share a register with `rega`.
+    pub fn with_copies(program: &Program, cfg: &Cfg, copies: &BTreeSet<usize>) -> Self {
+        let live = register_liveness(program, cfg);
+        let mut graph = Self::default();
+        for (idx, instruction) in program.code().iter().enumerate() {
+            for register in instruction.uses().into_iter().chain(instruction.defs()) {
+                graph.edges.entry(register).or_default();
+            }
+            let source = match instruction {
+                Instruction::Add { rega, .. } if copies.contains(&idx) => Some(*rega),
+                _ => None,
+            };
+            let defs = instruction.defs();
+            for def in &defs {
+                for other in live.after(idx).iter().chain(&defs) {
+                    if Some(*other) != source {
+                        graph.add_edge(*def, *other);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize) {
+        if a != b {
+            self.edges.entry(a).or_default().insert(b);
+            self.edges.entry(b).or_default().insert(a);
+        }
+    }
+
+    pub fn registers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.edges.keys().copied()
+    }
+
+    pub fn neighbours(&self, register: usize) -> &BTreeSet<usize> {
+        &self.edges[&register]
+    }
+
+    pub fn interferes(&self, a: usize, b: usize) -> bool {
+        self.edges.get(&a).is_some_and(|x| x.contains(&b))
+    }
+}
+
+/// Result of register allocation.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub program: Program,
+    /// Variable each spilled register of the input program lives in.
+    pub spilled: BTreeMap<usize, usize>,
+}
+
+/// Maps the registers of `program`, which may be any number of virtual ones,
+/// onto `register_count` physical registers by colouring the interference
+/// graph. Registers that don't fit are spilled to fresh variables, loaded
+/// into a short lived register before each read and stored after each write,
+/// and colouring is retried until it succeeds.
+///
+/// `Output` prints the name of its register, so a physical register that is
+/// output keeps its own name.
+pub fn allocate(program: &Program, register_count: usize) -> Result<Allocation, AllocationError> {
+    let mut current = program.clone();
+    let mut spilled = BTreeMap::new();
+    // Spill temporaries live for a single instruction, spilling them frees
+    // nothing
+    let mut temporaries = BTreeSet::new();
+    let mut next_variable = current
+        .code()
+        .iter()
+        .filter_map(|x| x.writes_variable().or(x.reads_variable()))
+        .max()
+        .map_or(0, |x| x + 1);
+
+    loop {
+        let cfg = Cfg::new(&current);
+        let graph = InterferenceGraph::new(&current, &cfg);
+        let precoloured = precoloured(&current, register_count);
+        match colour(&graph, &precoloured, &temporaries, register_count) {
+            Ok(colours) => {
+                let program = current.rewrite(|_, x| {
+                    vec![InstructionContainer::new(
+                        x.code().map_registers(|r| colours[&r]),
+                    )]
+                });
+                return Ok(Allocation { program, spilled });
+            }
+            Err(uncoloured) => {
+                if uncoloured.iter().any(|x| temporaries.contains(x)) {
+                    return Err(AllocationError::TooFewRegisters);
+                }
+                let slots: BTreeMap<usize, usize> = uncoloured
+                    .into_iter()
+                    .map(|x| {
+                        next_variable += 1;
+                        (x, next_variable - 1)
+                    })
+                    .collect();
+                current = spill(&current, &slots, &mut temporaries);
+                spilled.extend(slots);
+            }
+        }
+    }
+}
+
+/// Physical registers printed by an `Output` have to keep their name.
+fn precoloured(program: &Program, register_count: usize) -> BTreeMap<usize, usize> {
+    program
+        .code()
+        .iter()
+        .filter_map(|x| match x {
+            Instruction::Output(register) if *register < register_count => {
+                Some((*register, *register))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Chaitin-Briggs colouring: registers with fewer than `k` neighbours are
+/// removed until none are left, picking the most constrained spillable
+/// register optimistically when every remaining one has `k` or more. Colours
+/// are then handed out in reverse, and the registers left without one are
+/// returned as the ones to spill.
+fn colour(
+    graph: &InterferenceGraph,
+    precoloured: &BTreeMap<usize, usize>,
+    unspillable: &BTreeSet<usize>,
+    k: usize,
+) -> Result<BTreeMap<usize, usize>, Vec<usize>> {
+    let mut remaining: BTreeSet<usize> = graph
+        .registers()
+        .filter(|x| !precoloured.contains_key(x))
+        .collect();
+    let degree = |register: usize, remaining: &BTreeSet<usize>| {
+        graph
+            .neighbours(register)
+            .iter()
+            .filter(|x| remaining.contains(x) || precoloured.contains_key(x))
+            .count()
+    };
+
+    let mut stack = vec![];
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .copied()
+            .find(|x| degree(*x, &remaining) < k)
+            .or_else(|| {
+                remaining
+                    .iter()
+                    .copied()
+                    .filter(|x| !unspillable.contains(x))
+                    .max_by_key(|x| (degree(*x, &remaining), std::cmp::Reverse(*x)))
+            })
+            .or_else(|| remaining.iter().next().copied())
+            .unwrap();
+        remaining.remove(&next);
+        stack.push(next);
+    }
+
+    let mut colours = precoloured.clone();
+    let mut uncoloured = vec![];
+    while let Some(register) = stack.pop() {
+        let taken: BTreeSet<usize> = graph
+            .neighbours(register)
+            .iter()
+            .filter_map(|x| colours.get(x).copied())
+            .collect();
+        match (0..k).find(|x| !taken.contains(x)) {
+            Some(colour) => {
+                colours.insert(register, colour);
+            }
+            None => uncoloured.push(register),
+        }
+    }
+
+    if uncoloured.is_empty() {
+        Ok(colours)
+    } else {
+        Err(uncoloured)
+    }
+}
+
+/// Moves each register in `slots` into its variable. Every instruction
+/// touching one gets a fresh register for it, loaded before and stored after
+/// as needed. The variables are declared once up front, starting at 0 like
+/// the registers they replace.
+fn spill(
+    program: &Program,
+    slots: &BTreeMap<usize, usize>,
+    temporaries: &mut BTreeSet<usize>,
+) -> Program {
+    let code = program.code();
+    let mut next_register = code
+        .iter()
+        .flat_map(|x| x.uses().into_iter().chain(x.defs()))
+        .max()
+        .map_or(0, |x| x + 1);
+    let declarations: Vec<InstructionContainer> = slots
+        .values()
+        .map(|x| InstructionContainer::new(Instruction::Var(*x)))
+        .collect();
+
+    let (expanded, starts) = program.expand(|idx, instruction| {
+        let code = instruction.code();
+        let mut out = if idx == 0 {
+            declarations.clone()
+        } else {
+            vec![]
+        };
+        let touched: BTreeSet<usize> = code
+            .uses()
+            .into_iter()
+            .chain(code.defs())
+            .filter(|x| slots.contains_key(x))
+            .collect();
+        if touched.is_empty() {
+            out.push(*instruction);
+            return out;
+        }
+
+        let renamed: BTreeMap<usize, usize> = touched
+            .iter()
+            .map(|x| {
+                // A spilled register that is output is reloaded into itself
+                // so the printed name stays the same
+                let temporary = if matches!(code, Instruction::Output(_)) {
+                    *x
+                } else {
+                    next_register += 1;
+                    next_register - 1
+                };
+                temporaries.insert(temporary);
+                (*x, temporary)
+            })
+            .collect();
+        let uses = code.uses();
+        for (register, temporary) in &renamed {
+            if uses.contains(register) {
+                out.push(InstructionContainer::new(Instruction::Load {
+                    register: *temporary,
+                    variable: slots[register],
+                }));
+            }
+        }
+        out.push(InstructionContainer::new(
+            code.map_registers(|r| renamed.get(&r).copied().unwrap_or(r)),
+        ));
+        let defs = code.defs();
+        for (register, temporary) in &renamed {
+            if defs.contains(register) {
+                out.push(InstructionContainer::new(Instruction::Store {
+                    register: *temporary,
+                    variable: slots[register],
+                }));
+            }
+        }
+        out
+    });
+
+    // Jumps back to the start must not declare the variables again
+    let len = program.len();
+    let declared = declarations.len();
+    expanded.retarget(|_, target| match target {
+        0 => declared,
+        target => starts[target.min(len)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+
+    fn registers_used(program: &Program) -> BTreeSet<usize> {
+        program
+            .code()
+            .iter()
+            .flat_map(|x| x.uses().into_iter().chain(x.defs()))
+            .collect()
+    }
+
+    // Sums eight values that are all live at once into register 0
+    fn wide_sum() -> Program {
+        let mut code: Vec<Instruction> = (0..8).map(|x| set(4 + x, x as i32 + 1)).collect();
+        code.push(set(0, 0));
+        code.extend((0..8).map(|x| add(0, 4 + x, 0)));
+        code.push(out(0));
+        Program::new(code)
+    }
+
+    #[test]
+    fn registers_live_together_interfere() {
+        let program = Program::new(vec![set(0, 1), set(1, 2), add(0, 1, 2), out(2)]);
+        let graph = InterferenceGraph::new(&program, &Cfg::new(&program));
+        assert!(graph.interferes(0, 1));
+        assert!(!graph.interferes(0, 2));
+        assert!(!graph.interferes(1, 2));
+        assert_eq!(graph.registers().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn copies_may_share_a_register() {
+        let program = Program::new(vec![set(3, 0), set(0, 5), add(0, 3, 1), out(1), out(0)]);
+        let cfg = Cfg::new(&program);
+        assert!(InterferenceGraph::new(&program, &cfg).interferes(0, 1));
+        let graph = InterferenceGraph::with_copies(&program, &cfg, &BTreeSet::from([2]));
+        assert!(!graph.interferes(0, 1));
+    }
+
+    #[test]
+    fn colouring_respects_interference_and_precolouring() {
+        // Three registers live together
+        let program = Program::new(vec![
+            set(5, 1),
+            set(6, 2),
+            set(7, 3),
+            add(5, 6, 0),
+            add(0, 7, 0),
+            out(0),
+        ]);
+        let graph = InterferenceGraph::new(&program, &Cfg::new(&program));
+        let precoloured = BTreeMap::from([(0, 0)]);
+        assert!(colour(&graph, &precoloured, &BTreeSet::new(), 2).is_err());
+
+        let colours = colour(&graph, &precoloured, &BTreeSet::new(), 3).unwrap();
+        assert_eq!(colours[&0], 0);
+        for a in graph.registers() {
+            assert!(colours[&a] < 3);
+            for b in graph.neighbours(a) {
+                assert_ne!(colours[&a], colours[b], "{a} and {b} share a colour");
+            }
+        }
+    }
+
+    #[test]
+    fn outputs_keep_their_register() {
+        let program = Program::new(vec![set(9, 4), set(3, 1), add(9, 3, 3), out(3)]);
+        assert_eq!(precoloured(&program, 4), BTreeMap::from([(3, 3)]));
+        let allocation = allocate(&program, 4).unwrap();
+        assert!(allocation.spilled.is_empty());
+        assert!(allocation.program.code().contains(&out(3)));
+        let (_, expected) = VirtualMachine::new(10).exe(&program).unwrap();
+        let (_, found) = VirtualMachine::new(4).exe(&allocation.program).unwrap();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn more_virtual_registers_than_physical_ones_spill() {
+        let program = wide_sum();
+        let (_, expected) = VirtualMachine::new(12).exe(&program).unwrap();
+        for register_count in [3, 4, 6] {
+            let allocation = allocate(&program, register_count).unwrap();
+            assert!(!allocation.spilled.is_empty());
+            assert!(registers_used(&allocation.program)
+                .iter()
+                .all(|x| *x < register_count));
+            let (_, found) = VirtualMachine::new(register_count)
+                .exe(&allocation.program)
+                .unwrap();
+            assert_eq!(found, expected, "{}", allocation.program);
+        }
+        // Enough registers for everything at once needs no spilling
+        assert!(allocate(&program, 9).unwrap().spilled.is_empty());
+    }
+
+    #[test]
+    fn allocating_the_examples_keeps_their_output() {
+        for (program, mut vm) in examples() {
+            let allocation = allocate(&program, 4).unwrap();
+            assert!(registers_used(&allocation.program).iter().all(|x| *x < 4));
+            assert_same_output_on(&mut vm, &program, &allocation.program);
+        }
+    }
+
+    #[test]
+    fn a_single_register_is_too_few() {
+        assert!(matches!(
+            allocate(&wide_sum(), 1),
+            Err(AllocationError::TooFewRegisters)
+        ));
+    }
+}
@@ -0,0 +1,650 @@
+use crate::Instruction;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+pub mod mine;
+pub mod verify;
+
+// Rewrite rules over short runs of instructions, written as text:
+//
+//     # Comment
+//     rule store-load
+//         Store { register: $r, variable: $v }
+//         Load { register: $r, variable: $v }
+//     where $r != 3
+//     =>
+//         Store { register: $r, variable: $v }
+//
+// Instructions are written the way `Program` prints them, with `$name`
+// metavariables in place of any operand. A metavariable used more than once
+// has to match the same value everywhere. Conditions are optional:
+//
+//     where $a == $b / where $a != $b   compare operands
+//     where zero $r                     register $r holds 0 before the match
+//     where dead $r                     register $r is not read after it
+//
+// The replacement may be empty, which deletes the matched instructions.
+
+#[derive(Debug)]
+pub enum RuleError {
+    Io(std::io::Error),
+    Syntax { line: usize, message: String },
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::Io(error) => write!(f, "{error}"),
+            RuleError::Syntax { line, message } => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Add,
+    Sub,
+    Var,
+    Load,
+    Store,
+    SetReg,
+    VecAdd,
+    PCSetIfNotZero,
+    Output,
+}
+
+const OPCODES: [Opcode; 9] = [
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Var,
+    Opcode::Load,
+    Opcode::Store,
+    Opcode::SetReg,
+    Opcode::VecAdd,
+    Opcode::PCSetIfNotZero,
+    Opcode::Output,
+];
+
+impl Opcode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcode::Add => "Add",
+            Opcode::Sub => "Sub",
+            Opcode::Var => "Var",
+            Opcode::Load => "Load",
+            Opcode::Store => "Store",
+            Opcode::SetReg => "SetReg",
+            Opcode::VecAdd => "VecAdd",
+            Opcode::PCSetIfNotZero => "PCSetIfNotZero",
+            Opcode::Output => "Output",
+        }
+    }
+
+    /// Operand names in order, empty for the tuple-like `Var` and `Output`.
+    pub fn fields(&self) -> &'static [&'static str] {
+        match self {
+            Opcode::Add | Opcode::Sub => &["rega", "regb", "outreg"],
+            Opcode::Var | Opcode::Output => &[],
+            Opcode::Load | Opcode::Store => &["register", "variable"],
+            Opcode::SetReg => &["register", "constant"],
+            Opcode::VecAdd => &["a1r", "b1r", "r1", "a2r", "b2r", "r2"],
+            Opcode::PCSetIfNotZero => &["register", "jump_point"],
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.fields().len().max(1)
+    }
+
+    /// What each operand refers to.
+    pub fn kinds(&self) -> &'static [Kind] {
+        match self {
+            Opcode::Add | Opcode::Sub => &[Kind::Register; 3],
+            Opcode::Var => &[Kind::Variable],
+            Opcode::Load | Opcode::Store => &[Kind::Register, Kind::Variable],
+            Opcode::SetReg => &[Kind::Register, Kind::Constant],
+            Opcode::VecAdd => &[Kind::Register; 6],
+            Opcode::PCSetIfNotZero => &[Kind::Register, Kind::Jump],
+            Opcode::Output => &[Kind::Register],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    Register,
+    Variable,
+    Jump,
+    Constant,
+}
+
+/// Splits an instruction into its opcode and operands.
+pub fn decompose(instruction: &Instruction) -> (Opcode, Vec<i64>) {
+    let u = |x: usize| x as i64;
+    match *instruction {
+        Instruction::Add { rega, regb, outreg } => (Opcode::Add, vec![u(rega), u(regb), u(outreg)]),
+        Instruction::Sub { rega, regb, outreg } => (Opcode::Sub, vec![u(rega), u(regb), u(outreg)]),
+        Instruction::Var(variable) => (Opcode::Var, vec![u(variable)]),
+        Instruction::Load { register, variable } => (Opcode::Load, vec![u(register), u(variable)]),
+        Instruction::Store { register, variable } => {
+            (Opcode::Store, vec![u(register), u(variable)])
+        }
+        Instruction::SetReg { register, constant } => {
+            (Opcode::SetReg, vec![u(register), i64::from(constant)])
+        }
+        Instruction::VecAdd {
+            a1r,
+            b1r,
+            r1,
+            a2r,
+            b2r,
+            r2,
+        } => (
+            Opcode::VecAdd,
+            vec![u(a1r), u(b1r), u(r1), u(a2r), u(b2r), u(r2)],
+        ),
+        Instruction::PCSetIfNotZero {
+            register,
+            jump_point,
+        } => (Opcode::PCSetIfNotZero, vec![u(register), u(jump_point)]),
+        Instruction::Output(register) => (Opcode::Output, vec![u(register)]),
+    }
+}
+
+/// Builds an instruction back from its parts, `None` if an operand is out of
+/// range for its field.
+pub fn compose(opcode: Opcode, operands: &[i64]) -> Option<Instruction> {
+    if operands.len() != opcode.arity() {
+        return None;
+    }
+    let u = |x: usize| usize::try_from(operands[x]).ok();
+    Some(match opcode {
+        Opcode::Add => Instruction::Add {
+            rega: u(0)?,
+            regb: u(1)?,
+            outreg: u(2)?,
+        },
+        Opcode::Sub => Instruction::Sub {
+            rega: u(0)?,
+            regb: u(1)?,
+            outreg: u(2)?,
+        },
+        Opcode::Var => Instruction::Var(u(0)?),
+        Opcode::Load => Instruction::Load {
+            register: u(0)?,
+            variable: u(1)?,
+        },
+        Opcode::Store => Instruction::Store {
+            register: u(0)?,
+            variable: u(1)?,
+        },
+        Opcode::SetReg => Instruction::SetReg {
+            register: u(0)?,
+            constant: i32::try_from(operands[1]).ok()?,
+        },
+        Opcode::VecAdd => Instruction::VecAdd {
+            a1r: u(0)?,
+            b1r: u(1)?,
+            r1: u(2)?,
+            a2r: u(3)?,
+            b2r: u(4)?,
+            r2: u(5)?,
+        },
+        Opcode::PCSetIfNotZero => Instruction::PCSetIfNotZero {
+            register: u(0)?,
+            jump_point: u(1)?,
+        },
+        Opcode::Output => Instruction::Output(u(0)?),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Literal(i64),
+    Meta(String),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Literal(value) => write!(f, "{value}"),
+            Operand::Meta(name) => write!(f, "${name}"),
+        }
+    }
+}
+
+pub type Bindings = BTreeMap<String, i64>;
+
+/// An instruction with metavariables.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Template {
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl Template {
+    fn bind(&self, instruction: &Instruction, bindings: &mut Bindings) -> bool {
+        let (opcode, values) = decompose(instruction);
+        if opcode != self.opcode {
+            return false;
+        }
+        for (operand, value) in self.operands.iter().zip(values) {
+            match operand {
+                Operand::Literal(x) if *x != value => return false,
+                Operand::Literal(_) => {}
+                Operand::Meta(name) => {
+                    if *bindings.entry(name.clone()).or_insert(value) != value {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn instantiate(&self, bindings: &Bindings) -> Option<Instruction> {
+        let operands: Option<Vec<i64>> =
+            self.operands.iter().map(|x| resolve(x, bindings)).collect();
+        compose(self.opcode, &operands?)
+    }
+
+    fn metavariables(&self) -> impl Iterator<Item = &String> {
+        self.operands.iter().filter_map(|x| match x {
+            Operand::Meta(name) => Some(name),
+            Operand::Literal(_) => None,
+        })
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = self.opcode.fields();
+        if fields.is_empty() {
+            return write!(f, "{}({})", self.opcode.name(), self.operands[0]);
+        }
+        write!(f, "{} {{ ", self.opcode.name())?;
+        for (idx, (field, operand)) in fields.iter().zip(&self.operands).enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{field}: {operand}")?;
+        }
+        write!(f, " }}")
+    }
+}
+
+fn resolve(operand: &Operand, bindings: &Bindings) -> Option<i64> {
+    match operand {
+        Operand::Literal(value) => Some(*value),
+        Operand::Meta(name) => bindings.get(name).copied(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Equal(Operand, Operand),
+    NotEqual(Operand, Operand),
+    /// The register holds 0 when the match starts.
+    Zero(Operand),
+    /// The register's value isn't read after the match.
+    Dead(Operand),
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Equal(a, b) => write!(f, "{a} == {b}"),
+            Condition::NotEqual(a, b) => write!(f, "{a} != {b}"),
+            Condition::Zero(x) => write!(f, "zero {x}"),
+            Condition::Dead(x) => write!(f, "dead {x}"),
+        }
+    }
+}
+
+/// Facts about the program around a match, for the conditions that depend
+/// on more than the matched instructions.
+pub trait Context {
+    fn is_zero(&self, register: usize) -> bool;
+    fn is_dead(&self, register: usize) -> bool;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: Vec<Template>,
+    pub conditions: Vec<Condition>,
+    pub replacement: Vec<Template>,
+}
+
+impl Rule {
+    /// Matches the pattern against `window`, which must be exactly as long,
+    /// returning the metavariable values if every condition holds.
+    pub fn bind(&self, window: &[Instruction], context: &impl Context) -> Option<Bindings> {
+        if window.len() != self.pattern.len() {
+            return None;
+        }
+        let mut bindings = Bindings::new();
+        for (template, instruction) in self.pattern.iter().zip(window) {
+            if !template.bind(instruction, &mut bindings) {
+                return None;
+            }
+        }
+        let register = |x: &Operand| usize::try_from(resolve(x, &bindings)?).ok();
+        let holds = self.conditions.iter().all(|condition| match condition {
+            Condition::Equal(a, b) => resolve(a, &bindings) == resolve(b, &bindings),
+            Condition::NotEqual(a, b) => resolve(a, &bindings) != resolve(b, &bindings),
+            Condition::Zero(x) => register(x).is_some_and(|x| context.is_zero(x)),
+            Condition::Dead(x) => register(x).is_some_and(|x| context.is_dead(x)),
+        });
+        holds.then_some(bindings)
+    }
+
+    /// The replacement with `bindings` substituted in.
+    pub fn instantiate(&self, bindings: &Bindings) -> Option<Vec<Instruction>> {
+        self.replacement
+            .iter()
+            .map(|x| x.instantiate(bindings))
+            .collect()
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "rule {}", self.name)?;
+        for template in &self.pattern {
+            writeln!(f, "    {template}")?;
+        }
+        for condition in &self.conditions {
+            writeln!(f, "where {condition}")?;
+        }
+        writeln!(f, "=>")?;
+        for template in &self.replacement {
+            writeln!(f, "    {template}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads every rule in `text`.
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, RuleError> {
+    let mut rules = vec![];
+    // Rule being read and whether its `=>` has been seen
+    let mut current: Option<(Rule, bool, usize)> = None;
+    for (idx, raw) in text.lines().enumerate() {
+        let line = idx + 1;
+        let error = |message: String| RuleError::Syntax { line, message };
+        let text = raw.split('#').next().unwrap().trim();
+        if text.is_empty() {
+            continue;
+        }
+        if let Some(name) = text.strip_prefix("rule ") {
+            if let Some((rule, arrow, start)) = current.take() {
+                rules.push(finish(rule, arrow, start)?);
+            }
+            let rule = Rule {
+                name: name.trim().to_string(),
+                pattern: vec![],
+                conditions: vec![],
+                replacement: vec![],
+            };
+            current = Some((rule, false, line));
+            continue;
+        }
+        let Some((rule, arrow, _)) = current.as_mut() else {
+            return Err(error("expected `rule <name>`".to_string()));
+        };
+        if text == "=>" {
+            if *arrow {
+                return Err(error("second `=>` in rule".to_string()));
+            }
+            *arrow = true;
+        } else if let Some(condition) = text.strip_prefix("where ") {
+            if *arrow {
+                return Err(error("conditions go before `=>`".to_string()));
+            }
+            rule.conditions
+                .push(parse_condition(condition).map_err(error)?);
+        } else {
+            let template = parse_template(text).map_err(error)?;
+            if *arrow {
+                rule.replacement.push(template);
+            } else if !rule.conditions.is_empty() {
+                return Err(error("instructions can't follow conditions".to_string()));
+            } else {
+                rule.pattern.push(template);
+            }
+        }
+    }
+    if let Some((rule, arrow, start)) = current {
+        rules.push(finish(rule, arrow, start)?);
+    }
+    Ok(rules)
+}
+
+/// Checks a fully read rule.
+fn finish(rule: Rule, arrow: bool, line: usize) -> Result<Rule, RuleError> {
+    let error = |message: String| Err(RuleError::Syntax { line, message });
+    if !arrow {
+        return error(format!("rule `{}` has no `=>`", rule.name));
+    }
+    if rule.pattern.is_empty() {
+        return error(format!("rule `{}` has an empty pattern", rule.name));
+    }
+    let bound: Vec<&String> = rule
+        .pattern
+        .iter()
+        .flat_map(|x| x.metavariables())
+        .collect();
+    let used = rule
+        .replacement
+        .iter()
+        .flat_map(|x| x.operands.iter())
+        .chain(rule.conditions.iter().flat_map(|x| match x {
+            Condition::Equal(a, b) | Condition::NotEqual(a, b) => vec![a, b],
+            Condition::Zero(x) | Condition::Dead(x) => vec![x],
+        }));
+    for operand in used {
+        if let Operand::Meta(name) = operand {
+            if !bound.contains(&name) {
+                return error(format!(
+                    "`${name}` in rule `{}` isn't bound by its pattern",
+                    rule.name
+                ));
+            }
+        }
+    }
+    Ok(rule)
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let text = text.trim();
+    if let Some(name) = text.strip_prefix('$') {
+        if name.is_empty() || !name.chars().all(|x| x.is_alphanumeric() || x == '_') {
+            return Err(format!("bad metavariable `{text}`"));
+        }
+        return Ok(Operand::Meta(name.to_string()));
+    }
+    text.parse()
+        .map(Operand::Literal)
+        .map_err(|_| format!("expected a number or `$name`, found `{text}`"))
+}
+
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let text = text.trim();
+    if let Some(operand) = text.strip_prefix("zero ") {
+        return Ok(Condition::Zero(parse_operand(operand)?));
+    }
+    if let Some(operand) = text.strip_prefix("dead ") {
+        return Ok(Condition::Dead(parse_operand(operand)?));
+    }
+    if let Some((a, b)) = text.split_once("!=") {
+        return Ok(Condition::NotEqual(parse_operand(a)?, parse_operand(b)?));
+    }
+    if let Some((a, b)) = text.split_once("==") {
+        return Ok(Condition::Equal(parse_operand(a)?, parse_operand(b)?));
+    }
+    Err(format!("unknown condition `{text}`"))
+}
+
+fn parse_template(text: &str) -> Result<Template, String> {
+    let name_end = text
+        .find(|x: char| !x.is_alphanumeric())
+        .unwrap_or(text.len());
+    let (name, rest) = text.split_at(name_end);
+    let opcode = OPCODES
+        .iter()
+        .find(|x| x.name() == name)
+        .copied()
+        .ok_or_else(|| format!("unknown instruction `{name}`"))?;
+    let rest = rest.trim();
+
+    let fields = opcode.fields();
+    if fields.is_empty() {
+        let inner = rest
+            .strip_prefix('(')
+            .and_then(|x| x.strip_suffix(')'))
+            .ok_or_else(|| format!("expected `{name}(<operand>)`"))?;
+        return Ok(Template {
+            opcode,
+            operands: vec![parse_operand(inner)?],
+        });
+    }
+
+    let inner = rest
+        .strip_prefix('{')
+        .and_then(|x| x.strip_suffix('}'))
+        .ok_or_else(|| format!("expected `{name} {{ ... }}`"))?;
+    let mut operands: Vec<Option<Operand>> = vec![None; fields.len()];
+    for part in inner.split(',').filter(|x| !x.trim().is_empty()) {
+        let (field, value) = part
+            .split_once(':')
+            .ok_or_else(|| format!("expected `field: operand`, found `{}`", part.trim()))?;
+        let position = fields
+            .iter()
+            .position(|x| *x == field.trim())
+            .ok_or_else(|| format!("`{name}` has no field `{}`", field.trim()))?;
+        if operands[position].is_some() {
+            return Err(format!("field `{}` given twice", field.trim()));
+        }
+        operands[position] = Some(parse_operand(value)?);
+    }
+    let operands = operands
+        .into_iter()
+        .zip(fields)
+        .map(|(x, field)| x.ok_or_else(|| format!("missing field `{field}`")))
+        .collect::<Result<Vec<Operand>, String>>()?;
+    Ok(Template { opcode, operands })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORE_LOAD: &str = "
+        # Comment
+        rule store-load
+            Store { register: $r, variable: $v }
+            Load { register: $r, variable: $v }  # trailing comment
+        where $r != 3
+        =>
+            Store { register: $r, variable: $v }
+
+        rule drop-add-zero
+            Add { rega: $a, regb: $z, outreg: $a }
+        where zero $z
+        =>
+    ";
+
+    fn syntax_error(text: &str) -> usize {
+        match parse_rules(text) {
+            Err(RuleError::Syntax { line, .. }) => line,
+            other => panic!("expected a syntax error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = parse_rules(STORE_LOAD).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "store-load");
+        assert_eq!(rules[0].pattern.len(), 2);
+        assert_eq!(rules[0].pattern[1].opcode, Opcode::Load);
+        assert_eq!(
+            rules[0].conditions,
+            vec![Condition::NotEqual(
+                Operand::Meta("r".to_string()),
+                Operand::Literal(3)
+            )]
+        );
+        assert_eq!(rules[0].replacement.len(), 1);
+        assert_eq!(
+            rules[1].conditions,
+            vec![Condition::Zero(Operand::Meta("z".to_string()))]
+        );
+        assert!(rules[1].replacement.is_empty());
+    }
+
+    #[test]
+    fn printed_rules_parse_back() {
+        let rules = parse_rules(STORE_LOAD).unwrap();
+        let text: String = rules.iter().map(|x| x.to_string()).collect();
+        assert_eq!(parse_rules(&text).unwrap(), rules);
+    }
+
+    #[test]
+    fn binds_and_instantiates() {
+        struct Nothing;
+        impl Context for Nothing {
+            fn is_zero(&self, _: usize) -> bool {
+                false
+            }
+            fn is_dead(&self, _: usize) -> bool {
+                false
+            }
+        }
+        let rule = &parse_rules(STORE_LOAD).unwrap()[0];
+        let store = Instruction::Store {
+            register: 1,
+            variable: 4,
+        };
+        let load = Instruction::Load {
+            register: 1,
+            variable: 4,
+        };
+        let bindings = rule.bind(&[store, load], &Nothing).unwrap();
+        assert_eq!(rule.instantiate(&bindings), Some(vec![store]));
+
+        let other = Instruction::Load {
+            register: 2,
+            variable: 4,
+        };
+        assert!(rule.bind(&[store, other], &Nothing).is_none());
+        let excluded = Instruction::Store {
+            register: 3,
+            variable: 4,
+        };
+        let reload = Instruction::Load {
+            register: 3,
+            variable: 4,
+        };
+        assert!(rule.bind(&[excluded, reload], &Nothing).is_none());
+    }
+
+    #[test]
+    fn reports_the_line_of_syntax_errors() {
+        assert_eq!(syntax_error("Output($r)"), 1);
+        assert_eq!(syntax_error("rule a\n    Output($r)\n    Jump($r)\n=>"), 3);
+        assert_eq!(syntax_error("rule a\n    Output($r)\n=>\n=>"), 4);
+        assert_eq!(syntax_error("rule a\n    Output($r)\n=>\nwhere zero $r"), 4);
+        assert_eq!(syntax_error("rule a\n    SetReg { register: $r }\n=>"), 2);
+        assert_eq!(syntax_error("rule a\n    Output(x)\n=>"), 2);
+    }
+
+    #[test]
+    fn rejects_incomplete_rules() {
+        // Reported at the line the rule starts on
+        assert_eq!(syntax_error("\nrule a\n    Output($r)"), 2);
+        assert_eq!(syntax_error("rule a\n=>\n    Output(0)"), 1);
+        assert_eq!(syntax_error("rule a\n    Output(0)\n=>\n    Output($r)"), 1);
+    }
+}
@@ -0,0 +1,466 @@
+use crate::cfg::Cfg;
+use crate::dataflow::{register_liveness, solve, Solution};
+use crate::passes::constant_propagation::{ConstantAnalysis, Constants};
+use crate::passes::Peephole;
+use crate::rules::verify::Verifier;
+use crate::rules::{decompose, parse_rules, Condition, Kind, Operand, Rule, RuleError, Template};
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Longest stretch of the original program a mined rule may match.
+const MAX_WINDOW: usize = 6;
+
+const HEADER: &str = "# Rules mined from optimised programs by `rules::mine`, each checked by\n\
+                      # `rules::verify` before it was added.\n";
+
+/// How far a window is generalised. Rules are tried from the most general
+/// down, and the first one that verifies is kept.
+#[derive(Debug, Clone, Copy)]
+struct Variant {
+    abstract_constants: bool,
+    distinct: bool,
+    context: bool,
+}
+
+const VARIANTS: [Variant; 6] = [
+    Variant {
+        abstract_constants: true,
+        distinct: false,
+        context: false,
+    },
+    Variant {
+        abstract_constants: true,
+        distinct: true,
+        context: false,
+    },
+    Variant {
+        abstract_constants: true,
+        distinct: true,
+        context: true,
+    },
+    Variant {
+        abstract_constants: false,
+        distinct: false,
+        context: false,
+    },
+    Variant {
+        abstract_constants: false,
+        distinct: true,
+        context: false,
+    },
+    Variant {
+        abstract_constants: false,
+        distinct: true,
+        context: true,
+    },
+];
+
+/// Gives the operands of a window metavariable names, shared between the two
+/// sides of the rule.
+#[derive(Default)]
+struct Names {
+    names: BTreeMap<(Kind, Option<usize>), String>,
+    counts: BTreeMap<Kind, usize>,
+}
+
+impl Names {
+    fn name(&mut self, kind: Kind, key: Option<usize>) -> Operand {
+        let prefix = match kind {
+            Kind::Register => "r",
+            Kind::Variable => "v",
+            Kind::Jump => "j",
+            Kind::Constant => "k",
+        };
+        let count = self.counts.entry(kind).or_default();
+        let name = self
+            .names
+            .entry((kind, key))
+            .or_insert_with(|| {
+                *count += 1;
+                format!("{prefix}{}", *count - 1)
+            })
+            .clone();
+        Operand::Meta(name)
+    }
+
+    fn get(&self, kind: Kind, key: Option<usize>) -> Option<Operand> {
+        self.names
+            .get(&(kind, key))
+            .map(|x| Operand::Meta(x.clone()))
+    }
+
+    fn of_kind(&self, kind: Kind) -> Vec<Operand> {
+        self.names
+            .iter()
+            .filter(|((x, _), _)| *x == kind)
+            .map(|(_, name)| Operand::Meta(name.clone()))
+            .collect()
+    }
+}
+
+/// The stretches of `before` that differ in `after`, paired with what
+/// replaced them. Instructions are matched by identity rather than by
+/// value, so anything the search left alone lines up. A stretch that only
+/// inserts instructions takes the instruction before it along, since a
+/// rule needs something to match.
+pub fn changes(before: &Program, after: &Program) -> Vec<(Range<usize>, Range<usize>)> {
+    let a: Vec<usize> = before.iter().map(|x| x.id()).collect();
+    let b: Vec<usize> = after.iter().map(|x| x.id()).collect();
+
+    // Longest common subsequence, from the back so it can be walked forward
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut anchors = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            anchors.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    anchors.push((a.len(), b.len()));
+
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (x, y) in anchors {
+        if x > i || y > j {
+            if x > i {
+                changes.push((i..x, j..y));
+            } else if i > 0 {
+                changes.push((i - 1..x, j - 1..y));
+            } else if x < a.len() {
+                changes.push((i..x + 1, j..y + 1));
+            }
+        }
+        i = x + 1;
+        j = y + 1;
+    }
+    changes
+}
+
+/// Turns one change into a rule, `None` if a jump in the replacement goes
+/// somewhere the pattern can't name.
+fn generalise(
+    before: &Program,
+    after: &Program,
+    (from, to): &(Range<usize>, Range<usize>),
+    constants: &Solution<Option<Constants>>,
+    live: &Solution<BTreeSet<usize>>,
+    variant: Variant,
+) -> Option<Rule> {
+    let mut names = Names::default();
+    let target = |program: &Program, jump: i64| {
+        usize::try_from(jump)
+            .ok()
+            .and_then(|x| program.get(x))
+            .map(|x| x.id())
+    };
+
+    let mut pattern = vec![];
+    for idx in from.clone() {
+        let (opcode, values) = decompose(&before.get(idx)?.code());
+        let operands = opcode
+            .kinds()
+            .iter()
+            .zip(values)
+            .map(|(kind, value)| match kind {
+                Kind::Constant if !variant.abstract_constants => Operand::Literal(value),
+                Kind::Jump => names.name(*kind, target(before, value)),
+                _ => names.name(*kind, Some(value as usize)),
+            })
+            .collect();
+        pattern.push(Template { opcode, operands });
+    }
+
+    let mut replacement = vec![];
+    for idx in to.clone() {
+        let (opcode, values) = decompose(&after.get(idx)?.code());
+        let mut operands = vec![];
+        for (kind, value) in opcode.kinds().iter().zip(values) {
+            let key = match kind {
+                Kind::Jump => target(after, value),
+                _ => Some(value as usize),
+            };
+            operands.push(match names.get(*kind, key) {
+                Some(name) if *kind != Kind::Constant || variant.abstract_constants => name,
+                _ if *kind == Kind::Jump => return None,
+                _ => Operand::Literal(value),
+            });
+        }
+        replacement.push(Template { opcode, operands });
+    }
+
+    let mut conditions = vec![];
+    if variant.distinct {
+        for kind in [Kind::Register, Kind::Variable] {
+            let metas = names.of_kind(kind);
+            for (idx, a) in metas.iter().enumerate() {
+                for b in &metas[idx + 1..] {
+                    conditions.push(Condition::NotEqual(a.clone(), b.clone()));
+                }
+            }
+        }
+    }
+    if variant.context {
+        let window: Vec<_> = from.clone().filter_map(|x| before.get(x)).collect();
+        let used: BTreeSet<usize> = window.iter().flat_map(|x| x.code().uses()).collect();
+        let defined: BTreeSet<usize> = window.iter().flat_map(|x| x.code().defs()).collect();
+        let known = constants.before(from.start).as_ref();
+        for register in used {
+            if known.is_some_and(|x| x.register(register) == Some(0)) {
+                conditions.push(Condition::Zero(names.get(Kind::Register, Some(register))?));
+            }
+        }
+        for register in defined {
+            if !live.after(from.end - 1).contains(&register) {
+                conditions.push(Condition::Dead(names.get(Kind::Register, Some(register))?));
+            }
+        }
+    }
+
+    Some(Rule {
+        name: String::new(),
+        pattern,
+        conditions,
+        replacement,
+    })
+}
+
+/// The change, then the change with the unchanged instruction before it,
+/// after it, and both. A change is often only valid next to what the search
+/// left alone, like dropping a `Load` right after a `Store`.
+fn with_context(
+    before: &Program,
+    after: &Program,
+    (from, to): (Range<usize>, Range<usize>),
+) -> impl Iterator<Item = (Range<usize>, Range<usize>)> {
+    let same = |a: usize, b: usize| {
+        let a = before.get(a).map(|x| x.id());
+        a.is_some() && a == after.get(b).map(|x| x.id())
+    };
+    let previous = from.start > 0 && to.start > 0 && same(from.start - 1, to.start - 1);
+    let next = same(from.end, to.end);
+    let mut extended = vec![(from.clone(), to.clone())];
+    if previous {
+        extended.push((from.start - 1..from.end, to.start - 1..to.end));
+    }
+    if next {
+        extended.push((from.start..from.end + 1, to.start..to.end + 1));
+    }
+    if previous && next {
+        extended.push((from.start - 1..from.end + 1, to.start - 1..to.end + 1));
+    }
+    extended
+        .into_iter()
+        .filter(|(from, _)| from.len() <= MAX_WINDOW)
+}
+
+fn cost(templates: &[Template]) -> Option<usize> {
+    let mut bindings = BTreeMap::new();
+    for template in templates {
+        for name in template.metavariables() {
+            bindings.insert(name.clone(), 0);
+        }
+    }
+    let code: Option<Vec<_>> = templates.iter().map(|x| x.instantiate(&bindings)).collect();
+    Some(Program::new(code?).cost())
+}
+
+/// Rewrite rules learned from optimised programs, kept in a rule file that
+/// `Peephole` can read.
+pub struct RuleDatabase {
+    path: PathBuf,
+    rules: Vec<Rule>,
+    verifier: Verifier,
+}
+
+impl RuleDatabase {
+    /// Reads the database at `path`, which starts out empty if the file
+    /// doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RuleError> {
+        let path = path.as_ref().to_path_buf();
+        let rules = match std::fs::read_to_string(&path) {
+            Ok(text) => parse_rules(&text)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(RuleError::Io(error)),
+        };
+        Ok(Self {
+            path,
+            rules,
+            verifier: Verifier::new(),
+        })
+    }
+
+    pub fn with_verifier(mut self, verifier: Verifier) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn peephole(&self) -> Peephole {
+        Peephole::new(self.rules.clone())
+    }
+
+    /// Learns rules from the ways `after` improved on `before`. Each change
+    /// is generalised over its registers, variables, constants and jump
+    /// targets, and the most general form that is cheaper and passes
+    /// verification is added unless the database already has it. Returns
+    /// how many rules were new.
+    pub fn record(&mut self, before: &Program, after: &Program) -> usize {
+        let cfg = Cfg::new(before);
+        let constants = solve(&ConstantAnalysis, before, &cfg);
+        let live = register_liveness(before, &cfg);
+
+        let mut added = 0;
+        for change in changes(before, after) {
+            let found = with_context(before, after, change).find_map(|change| {
+                VARIANTS.iter().find_map(|variant| {
+                    let rule = generalise(before, after, &change, &constants, &live, *variant)?;
+                    (cost(&rule.replacement)? < cost(&rule.pattern)? && self.verifies(&rule))
+                        .then_some(rule)
+                })
+            });
+            let Some(mut rule) = found else {
+                continue;
+            };
+            let known = self.rules.iter().any(|x| {
+                x.pattern == rule.pattern
+                    && x.conditions == rule.conditions
+                    && x.replacement == rule.replacement
+            });
+            if !known {
+                rule.name = format!("mined-{}", self.rules.len() + 1);
+                self.rules.push(rule);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Checks the rule with enough registers and variables for its
+    /// distinctness conditions to be satisfiable.
+    fn verifies(&self, rule: &Rule) -> bool {
+        let mut registers = HashSet::new();
+        let mut variables = HashSet::new();
+        for template in &rule.pattern {
+            for (kind, operand) in template.opcode.kinds().iter().zip(&template.operands) {
+                match kind {
+                    Kind::Register => registers.insert(operand),
+                    Kind::Variable => variables.insert(operand),
+                    _ => false,
+                };
+            }
+        }
+        let verifier = self
+            .verifier
+            .clone()
+            .with_registers(self.verifier.registers.max(registers.len()))
+            .with_variables(self.verifier.variables.max(variables.len()));
+        verifier.check(rule).is_ok_and(|instances| instances > 0)
+    }
+
+    pub fn save(&self) -> Result<(), RuleError> {
+        let rules: Vec<String> = self.rules.iter().map(|x| x.to_string()).collect();
+        let text = format!("{HEADER}\n{}", rules.join("\n"));
+        std::fs::write(&self.path, text).map_err(RuleError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::Pass;
+    use crate::testing::*;
+
+    fn database(name: &str) -> RuleDatabase {
+        let file = format!("mined-{name}-{}.rules", std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = std::fs::remove_file(&path);
+        RuleDatabase::open(path).unwrap()
+    }
+
+    /// `program` without the instructions at `indices`, keeping the ids of
+    /// the rest the way a search does.
+    fn without(program: &Program, indices: &[usize]) -> Program {
+        program.rewrite(|idx, x| {
+            if indices.contains(&idx) {
+                vec![]
+            } else {
+                vec![*x]
+            }
+        })
+    }
+
+    #[test]
+    fn finds_changed_stretches() {
+        let before = Program::new(vec![set(0, 1), set(1, 2), set(2, 3), out(2)]);
+        let after = without(&before, &[1]);
+        assert_eq!(changes(&before, &after), vec![(1..2, 1..1)]);
+    }
+
+    #[test]
+    fn records_a_verified_rule_once() {
+        let before = Program::new(vec![set(0, 1), set(0, 2), out(0)]);
+        let after = without(&before, &[0]);
+        let mut database = database("once");
+        assert!(database.record(&before, &after) > 0);
+        assert!(database
+            .rules()
+            .iter()
+            .all(|x| x.name.starts_with("mined-")));
+        let count = database.rules().len();
+        assert_eq!(database.record(&before, &after), 0);
+        assert_eq!(database.rules().len(), count);
+
+        // The rule applies to the same change elsewhere
+        let elsewhere = Program::new(vec![set(2, 7), set(2, 8), out(2)]);
+        let rewritten = database.peephole().run(&elsewhere);
+        assert_eq!(rewritten.code(), vec![set(2, 8), out(2)]);
+    }
+
+    #[test]
+    fn refuses_changes_that_are_not_valid_in_general() {
+        // Removing the first write is only fine because register 0 isn't
+        // printed, which no window around it shows
+        let before = Program::new(vec![set(0, 1), set(1, 2), out(1)]);
+        let after = without(&before, &[0]);
+        let mut database = database("general");
+        database.record(&before, &after);
+        for rule in database.rules() {
+            assert!(rule
+                .conditions
+                .iter()
+                .any(|x| matches!(x, Condition::Dead(_))));
+        }
+    }
+
+    #[test]
+    fn saves_and_reopens() {
+        let before = Program::new(vec![set(0, 1), set(0, 2), out(0)]);
+        let after = without(&before, &[0]);
+        let mut database = database("save");
+        database.record(&before, &after);
+        database.save().unwrap();
+        let reopened = RuleDatabase::open(&database.path).unwrap();
+        assert_eq!(reopened.rules(), database.rules());
+        std::fs::remove_file(&database.path).unwrap();
+    }
+}
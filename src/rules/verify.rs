@@ -0,0 +1,476 @@
+use crate::rules::{Bindings, Condition, Context, Kind, Operand, Rule};
+use crate::vm::ExecutionError;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use crate::VirtualMachine;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+
+/// Values the register file and memory start from, biased towards the ones
+/// where checked arithmetic overflows.
+const INTERESTING: [i32; 9] = [
+    0,
+    1,
+    -1,
+    2,
+    -2,
+    i32::MAX,
+    i32::MIN,
+    i32::MAX - 1,
+    i32::MIN + 1,
+];
+
+/// Where a jump metavariable may point, relative to the test program.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    WindowStart,
+    Jumped,
+    Exit,
+}
+
+/// A rule instance where the two sides disagree.
+#[derive(Debug)]
+pub struct Counterexample {
+    pub rule: String,
+    pub bindings: Bindings,
+    pub registers: BTreeMap<usize, i32>,
+    pub memory: BTreeMap<usize, i32>,
+    pub before: Vec<Instruction>,
+    pub after: Vec<Instruction>,
+    pub expected: Vec<String>,
+    pub found: Result<Vec<String>, ExecutionError>,
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "rule `{}` is wrong", self.rule)?;
+        let bindings: Vec<String> = self
+            .bindings
+            .iter()
+            .map(|(name, value)| format!("${name} = {value}"))
+            .collect();
+        writeln!(f, "  with {}", bindings.join(", "))?;
+        writeln!(
+            f,
+            "  registers {:?}, memory {:?}",
+            self.registers, self.memory
+        )?;
+        writeln!(f, "  pattern:")?;
+        for instruction in &self.before {
+            writeln!(f, "    {instruction:?}")?;
+        }
+        writeln!(f, "  replacement:")?;
+        for instruction in &self.after {
+            writeln!(f, "    {instruction:?}")?;
+        }
+        writeln!(f, "  expected {:?}", self.expected)?;
+        write!(f, "  found {:?}", self.found)
+    }
+}
+
+/// Registers the test harness forces to 0 or leaves unobserved, following
+/// the rule's conditions.
+struct Harness {
+    zero: BTreeSet<usize>,
+    dead: BTreeSet<usize>,
+}
+
+impl Context for Harness {
+    fn is_zero(&self, register: usize) -> bool {
+        self.zero.contains(&register)
+    }
+
+    fn is_dead(&self, register: usize) -> bool {
+        self.dead.contains(&register)
+    }
+}
+
+/// Checks rules by brute force. Every metavariable ranges over a small
+/// domain for its kind of operand, and each instance runs on random starting
+/// registers and memory, followed by code printing every observable register
+/// and variable. Each run is repeated with every variable left undeclared in
+/// turn. Jumps may go back to the start of the match, to a block that marks
+/// it was taken, or out of the program. Wherever the pattern runs without
+/// error, the replacement has to run without error and print the same
+/// thing. Passing is evidence, not proof.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    pub(crate) registers: usize,
+    pub(crate) variables: usize,
+    constants: Vec<i32>,
+    trials: usize,
+    max_instances: usize,
+    seed: u64,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self {
+            registers: 3,
+            variables: 2,
+            constants: vec![0, 1, -1, 2, i32::MAX, i32::MIN],
+            trials: 8,
+            max_instances: 20_000,
+            seed: 0,
+        }
+    }
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_registers(mut self, registers: usize) -> Self {
+        self.registers = registers;
+        self
+    }
+
+    pub fn with_variables(mut self, variables: usize) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    pub fn with_constants(mut self, constants: Vec<i32>) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    pub fn with_trials(mut self, trials: usize) -> Self {
+        self.trials = trials;
+        self
+    }
+
+    pub fn with_max_instances(mut self, max_instances: usize) -> Self {
+        self.max_instances = max_instances;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Tests the rule, returning how many instances were run or the first
+    /// one found where its sides differ.
+    pub fn check(&self, rule: &Rule) -> Result<usize, Box<Counterexample>> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let domains = domains(rule);
+        let names: Vec<&String> = domains.keys().collect();
+        let mut choices = vec![0; names.len()];
+        let mut instances = 0;
+
+        'instances: loop {
+            if instances >= self.max_instances {
+                break;
+            }
+            let mut bindings = Bindings::new();
+            let mut targets = BTreeMap::new();
+            for (name, choice) in names.iter().zip(&choices) {
+                match domains[*name] {
+                    Kind::Jump => {
+                        targets.insert((*name).clone(), *choice);
+                    }
+                    domain => {
+                        bindings.insert((*name).clone(), self.value(domain, *choice));
+                    }
+                }
+            }
+            if let Some(checked) = self.check_instance(rule, bindings, &targets, &mut rng)? {
+                instances += checked;
+            }
+
+            // Next combination, odometer style
+            for (idx, name) in names.iter().enumerate() {
+                choices[idx] += 1;
+                if choices[idx] < self.size(domains[*name]) {
+                    continue 'instances;
+                }
+                choices[idx] = 0;
+            }
+            break;
+        }
+        Ok(instances)
+    }
+
+    /// Splits `rules` into the ones that pass and counterexamples for the
+    /// rest.
+    pub fn filter(&self, rules: Vec<Rule>) -> (Vec<Rule>, Vec<Counterexample>) {
+        let mut passed = vec![];
+        let mut failed = vec![];
+        for rule in rules {
+            match self.check(&rule) {
+                Ok(_) => passed.push(rule),
+                Err(counterexample) => failed.push(*counterexample),
+            }
+        }
+        (passed, failed)
+    }
+
+    fn size(&self, domain: Kind) -> usize {
+        match domain {
+            Kind::Register => self.registers,
+            Kind::Variable => self.variables,
+            Kind::Jump => 3,
+            Kind::Constant => self.constants.len(),
+        }
+    }
+
+    fn value(&self, domain: Kind, choice: usize) -> i64 {
+        match domain {
+            Kind::Constant => i64::from(self.constants[choice]),
+            _ => choice as i64,
+        }
+    }
+
+    /// Runs one instance of the rule, `None` if its conditions don't hold.
+    fn check_instance(
+        &self,
+        rule: &Rule,
+        mut bindings: Bindings,
+        targets: &BTreeMap<String, usize>,
+        rng: &mut StdRng,
+    ) -> Result<Option<usize>, Box<Counterexample>> {
+        let register = |x: &Operand, bindings: &Bindings| match x {
+            Operand::Literal(x) => usize::try_from(*x).ok(),
+            Operand::Meta(name) => bindings.get(name).and_then(|x| usize::try_from(*x).ok()),
+        };
+        let mut harness = Harness {
+            zero: BTreeSet::new(),
+            dead: BTreeSet::new(),
+        };
+        for condition in &rule.conditions {
+            match condition {
+                Condition::Zero(x) => harness.zero.extend(register(x, &bindings)),
+                Condition::Dead(x) => harness.dead.extend(register(x, &bindings)),
+                _ => {}
+            }
+        }
+
+        // Registers and variables the instance touches, jump points aside
+        let mut placeholder = bindings.clone();
+        for name in targets.keys() {
+            placeholder.insert(name.clone(), 0);
+        }
+        let Some(window) = pattern(rule, &placeholder) else {
+            return Ok(None);
+        };
+        let mut registers: BTreeSet<usize> = (0..self.registers).collect();
+        let mut variables: BTreeSet<usize> = (0..self.variables).collect();
+        for instruction in &window {
+            registers.extend(instruction.uses());
+            registers.extend(instruction.defs());
+            variables.extend(instruction.reads_variable());
+            variables.extend(instruction.writes_variable());
+        }
+        let scratch = registers.iter().max().unwrap() + 1;
+        let observed: Vec<usize> = registers
+            .iter()
+            .copied()
+            .filter(|x| !harness.dead.contains(x))
+            .collect();
+
+        // Setup, the match, what falls through and what jumped
+        let mut epilogue = vec![];
+        for register in &observed {
+            epilogue.push(Instruction::Output(*register));
+        }
+        for variable in &variables {
+            epilogue.push(Instruction::Load {
+                register: scratch,
+                variable: *variable,
+            });
+            epilogue.push(Instruction::Output(scratch));
+        }
+        let start = registers.len();
+        let jumped = start + window.len() + epilogue.len() + 2;
+        let exit = jumped + 2 + epilogue.len();
+        for (name, choice) in targets {
+            let target = match [Target::WindowStart, Target::Jumped, Target::Exit][*choice] {
+                Target::WindowStart => start,
+                Target::Jumped => jumped,
+                Target::Exit => exit,
+            };
+            bindings.insert(name.clone(), target as i64);
+        }
+        let Some(window) = pattern(rule, &bindings) else {
+            return Ok(None);
+        };
+        if rule.bind(&window, &harness).is_none() {
+            return Ok(None);
+        }
+        let Some(replacement) = rule.instantiate(&bindings) else {
+            return Ok(None);
+        };
+
+        let mut code: Vec<Instruction> = registers
+            .iter()
+            .map(|x| Instruction::SetReg {
+                register: *x,
+                constant: 0,
+            })
+            .collect();
+        code.extend(&window);
+        code.extend(&epilogue);
+        code.push(Instruction::SetReg {
+            register: scratch,
+            constant: 1,
+        });
+        code.push(Instruction::PCSetIfNotZero {
+            register: scratch,
+            jump_point: exit,
+        });
+        code.push(Instruction::SetReg {
+            register: scratch,
+            constant: 7,
+        });
+        code.push(Instruction::Output(scratch));
+        code.extend(&epilogue);
+
+        for _ in 0..self.trials {
+            let mut initial = BTreeMap::new();
+            for (idx, register) in registers.iter().enumerate() {
+                let value = if harness.zero.contains(register) {
+                    0
+                } else {
+                    random_value(rng)
+                };
+                code[idx] = Instruction::SetReg {
+                    register: *register,
+                    constant: value,
+                };
+                initial.insert(*register, value);
+            }
+            let declared: HashMap<usize, i32> =
+                variables.iter().map(|x| (*x, random_value(rng))).collect();
+
+            // Every variable declared up front, then each one left out in
+            // turn, so a replacement can't rely on a declaration the pattern
+            // makes itself
+            for missing in std::iter::once(None).chain(variables.iter().map(Some)) {
+                let mut memory = declared.clone();
+                if let Some(missing) = missing {
+                    memory.remove(missing);
+                }
+                let before = Program::new(code.clone());
+                let after = before.rewrite(|idx, instruction| {
+                    if idx == start {
+                        replacement
+                            .iter()
+                            .map(|x| InstructionContainer::new(*x))
+                            .collect()
+                    } else if (start..start + window.len()).contains(&idx) {
+                        vec![]
+                    } else {
+                        vec![*instruction]
+                    }
+                });
+                let mut vm = VirtualMachine::from_memory_state(scratch + 1, memory.clone());
+                let Ok((_, expected)) = vm.exe(&before) else {
+                    continue;
+                };
+                // Includes the replacement failing where the pattern doesn't
+                let found = vm.exe(&after).map(|(_, x)| x);
+                if found.as_ref().ok() != Some(&expected) {
+                    return Err(Box::new(Counterexample {
+                        rule: rule.name.clone(),
+                        bindings,
+                        registers: initial,
+                        memory: memory.into_iter().collect(),
+                        before: window,
+                        after: replacement,
+                        expected,
+                        found,
+                    }));
+                }
+            }
+        }
+        Ok(Some(1))
+    }
+}
+
+fn pattern(rule: &Rule, bindings: &Bindings) -> Option<Vec<Instruction>> {
+    rule.pattern
+        .iter()
+        .map(|x| x.instantiate(bindings))
+        .collect()
+}
+
+fn random_value(rng: &mut StdRng) -> i32 {
+    if rng.gen_bool(0.5) {
+        INTERESTING[rng.gen_range(0..INTERESTING.len())]
+    } else {
+        rng.gen_range(-100..=100)
+    }
+}
+
+/// The domain of every metavariable, taken from the operands it appears as.
+/// One used as both a register and a constant ranges over registers.
+fn domains(rule: &Rule) -> BTreeMap<String, Kind> {
+    let mut domains: BTreeMap<String, Kind> = BTreeMap::new();
+    for template in rule.pattern.iter().chain(&rule.replacement) {
+        for (field, operand) in template.opcode.kinds().iter().zip(&template.operands) {
+            if let Operand::Meta(name) = operand {
+                let domain = domains.entry(name.clone()).or_insert(*field);
+                *domain = (*domain).min(*field);
+            }
+        }
+    }
+    domains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::parse_rules;
+
+    fn rule(text: &str) -> Rule {
+        parse_rules(text).unwrap().remove(0)
+    }
+
+    #[test]
+    fn correct_rules_pass() {
+        let store_load = rule(
+            "rule store-load
+                Store { register: $r, variable: $v }
+                Load { register: $r, variable: $v }
+            =>
+                Store { register: $r, variable: $v }",
+        );
+        assert!(Verifier::new().check(&store_load).unwrap() > 0);
+    }
+
+    #[test]
+    fn wrong_results_are_caught() {
+        let wrong = rule(
+            "rule add-is-sub
+                Add { rega: $a, regb: $b, outreg: $c }
+            =>
+                Sub { rega: $a, regb: $b, outreg: $c }",
+        );
+        let counterexample = Verifier::new().check(&wrong).unwrap_err();
+        assert_eq!(counterexample.rule, "add-is-sub");
+        assert!(counterexample.found.is_ok());
+    }
+
+    #[test]
+    fn dropping_a_declaration_is_caught() {
+        let dropped = rule(
+            "rule drop-var
+                Var($v)
+                Store { register: $r, variable: $v }
+            =>
+                Store { register: $r, variable: $v }",
+        );
+        let counterexample = Verifier::new().check(&dropped).unwrap_err();
+        assert!(matches!(
+            counterexample.found,
+            Err(ExecutionError::VariableNotFound)
+        ));
+        let variable = counterexample.bindings["v"] as usize;
+        assert!(!counterexample.memory.contains_key(&variable));
+    }
+}
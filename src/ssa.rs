@@ -0,0 +1,834 @@
+use crate::cfg::Cfg;
+use crate::dataflow::register_liveness;
+use crate::regalloc::InterferenceGraph;
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Where an SSA value is defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Definition {
+    /// The register's initial 0, for reads with no definition before them.
+    Entry,
+    /// A phi at the start of the block.
+    Phi(usize),
+    /// The instruction at the index.
+    Instruction(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    /// Register of the original program the value is a version of.
+    pub register: usize,
+    pub definition: Definition,
+}
+
+/// Picks between versions of a register where control flow meets. An
+/// argument's edge is the predecessor block, or `None` for the program entry
+/// when block 0 is also a jump target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phi {
+    pub value: usize,
+    pub arguments: Vec<(Option<usize>, usize)>,
+}
+
+/// Something reading a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum User {
+    Instruction(usize),
+    /// The phi defining the value, in the block.
+    Phi(usize, usize),
+}
+
+/// Static single assignment view of a program. Instructions keep their
+/// indices and jump points, but their register operands name values, each
+/// defined exactly once. Phis are pruned: a block only gets one for a
+/// register that is live into it.
+#[derive(Debug, Clone)]
+pub struct Ssa {
+    cfg: Cfg,
+    code: Vec<Instruction>,
+    values: Vec<Value>,
+    phis: Vec<Vec<Phi>>,
+}
+
+/// Register moves `(from, to)` between values, in order.
+type Moves = Vec<(usize, usize)>;
+
+/// Current version of each register during renaming.
+struct Renamer {
+    stacks: HashMap<usize, Vec<usize>>,
+    entries: HashMap<usize, usize>,
+}
+
+impl Ssa {
+    pub fn new(program: &Program) -> Self {
+        let cfg = Cfg::new(program);
+        let live = register_liveness(program, &cfg);
+        let mut ssa = Self {
+            code: program.code(),
+            values: vec![],
+            phis: vec![vec![]; cfg.len()],
+            cfg,
+        };
+        if ssa.code.is_empty() {
+            return ssa;
+        }
+
+        let idom = ssa.immediate_dominators();
+        let frontiers = ssa.dominance_frontiers(&idom);
+
+        // Phis go on the iterated dominance frontier of every definition
+        let mut defined_in: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for (block, x) in ssa.cfg.blocks().iter().enumerate() {
+            for idx in x.range() {
+                for register in ssa.code[idx].defs() {
+                    defined_in.entry(register).or_default().insert(block);
+                }
+            }
+        }
+        let mut phi_registers: Vec<Vec<usize>> = vec![vec![]; ssa.cfg.len()];
+        for (register, blocks) in defined_in {
+            let mut worklist: Vec<usize> = blocks.into_iter().collect();
+            let mut placed = BTreeSet::new();
+            while let Some(block) = worklist.pop() {
+                for frontier in &frontiers[block] {
+                    let start = ssa.cfg.block(*frontier).start;
+                    if live.before(start).contains(&register) && placed.insert(*frontier) {
+                        phi_registers[*frontier].push(register);
+                        worklist.push(*frontier);
+                    }
+                }
+            }
+        }
+        for (block, registers) in phi_registers.into_iter().enumerate() {
+            for register in registers {
+                let value = ssa.define(register, Definition::Phi(block));
+                ssa.phis[block].push(Phi {
+                    value,
+                    arguments: vec![],
+                });
+            }
+        }
+
+        let mut renamer = Renamer {
+            stacks: HashMap::new(),
+            entries: HashMap::new(),
+        };
+        if !ssa.cfg.block(0).predecessors.is_empty() {
+            for phi in 0..ssa.phis[0].len() {
+                let register = ssa.values[ssa.phis[0][phi].value].register;
+                let value = ssa.current(&mut renamer, register);
+                ssa.phis[0][phi].arguments.push((None, value));
+            }
+        }
+        let mut children: Vec<Vec<usize>> = vec![vec![]; ssa.cfg.len()];
+        for (block, parent) in idom.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[*parent].push(block);
+            }
+        }
+        ssa.rename(0, &children, &mut renamer);
+
+        // Unreachable blocks never run, any consistent naming will do
+        let reachable: BTreeSet<usize> = ssa.cfg.reverse_postorder().into_iter().collect();
+        for block in 0..ssa.cfg.len() {
+            if !reachable.contains(&block) {
+                ssa.rename_block(block, &mut renamer);
+                for stack in renamer.stacks.values_mut() {
+                    stack.clear();
+                }
+            }
+        }
+        ssa
+    }
+
+    pub fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn value(&self, value: usize) -> Value {
+        self.values[value]
+    }
+
+    pub fn phis(&self, block: usize) -> &[Phi] {
+        &self.phis[block]
+    }
+
+    /// Instruction at `index`, operands naming values.
+    pub fn instruction(&self, index: usize) -> Instruction {
+        self.code[index]
+    }
+
+    /// Replaces the instruction at `index`. It must define the same values as
+    /// the one it replaces, if any, and only read values.
+    pub fn replace(&mut self, index: usize, instruction: Instruction) {
+        self.code[index] = instruction;
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Every instruction and phi reading `value`, the def-use chain.
+    pub fn users(&self, value: usize) -> Vec<User> {
+        let mut users: Vec<User> = self
+            .code
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.uses().contains(&value))
+            .map(|(idx, _)| User::Instruction(idx))
+            .collect();
+        for (block, phis) in self.phis.iter().enumerate() {
+            for phi in phis {
+                if phi.arguments.iter().any(|(_, x)| *x == value) {
+                    users.push(User::Phi(block, phi.value));
+                }
+            }
+        }
+        users
+    }
+
+    fn define(&mut self, register: usize, definition: Definition) -> usize {
+        self.values.push(Value {
+            register,
+            definition,
+        });
+        self.values.len() - 1
+    }
+
+    fn current(&mut self, renamer: &mut Renamer, register: usize) -> usize {
+        if let Some(value) = renamer.stacks.get(&register).and_then(|x| x.last()) {
+            return *value;
+        }
+        match renamer.entries.get(&register) {
+            Some(value) => *value,
+            None => {
+                let value = self.define(register, Definition::Entry);
+                renamer.entries.insert(register, value);
+                value
+            }
+        }
+    }
+
+    /// Renames the block's phis and instructions and fills in its successors'
+    /// phi arguments, returning the registers it pushed versions for.
+    fn rename_block(&mut self, block: usize, renamer: &mut Renamer) -> Vec<usize> {
+        let mut pushed = vec![];
+        for phi in &self.phis[block] {
+            let register = self.values[phi.value].register;
+            renamer.stacks.entry(register).or_default().push(phi.value);
+            pushed.push(register);
+        }
+        for idx in self.cfg.block(block).range() {
+            let instruction = self.code[idx];
+            let mut current = HashMap::new();
+            for register in instruction.uses() {
+                current.insert(register, self.current(renamer, register));
+            }
+            let instruction = instruction.map_uses(|r| current[&r]);
+            let mut defined = HashMap::new();
+            for register in instruction.defs() {
+                let value = self.define(register, Definition::Instruction(idx));
+                defined.insert(register, value);
+                renamer.stacks.entry(register).or_default().push(value);
+                pushed.push(register);
+            }
+            self.code[idx] = instruction.map_defs(|r| defined[&r]);
+        }
+        for succ in self.cfg.block(block).successors.clone() {
+            for phi in 0..self.phis[succ].len() {
+                let register = self.values[self.phis[succ][phi].value].register;
+                let value = self.current(renamer, register);
+                self.phis[succ][phi].arguments.push((Some(block), value));
+            }
+        }
+        pushed
+    }
+
+    fn rename(&mut self, block: usize, children: &[Vec<usize>], renamer: &mut Renamer) {
+        let pushed = self.rename_block(block, renamer);
+        for child in &children[block] {
+            self.rename(*child, children, renamer);
+        }
+        for register in pushed {
+            renamer.stacks.get_mut(&register).unwrap().pop();
+        }
+    }
+
+    fn immediate_dominators(&self) -> Vec<Option<usize>> {
+        let dominators = self.cfg.dominators();
+        let reachable: BTreeSet<usize> = self.cfg.reverse_postorder().into_iter().collect();
+        (0..self.cfg.len())
+            .map(|block| {
+                if block == 0 || !reachable.contains(&block) {
+                    return None;
+                }
+                // The closest strict dominator is the one with most dominators
+                dominators[block]
+                    .iter()
+                    .filter(|x| **x != block)
+                    .max_by_key(|x| dominators[**x].len())
+                    .copied()
+            })
+            .collect()
+    }
+
+    fn dominance_frontiers(&self, idom: &[Option<usize>]) -> Vec<BTreeSet<usize>> {
+        let reachable: BTreeSet<usize> = self.cfg.reverse_postorder().into_iter().collect();
+        let mut frontiers = vec![BTreeSet::new(); self.cfg.len()];
+        for block in &reachable {
+            let predecessors: Vec<usize> = self
+                .cfg
+                .block(*block)
+                .predecessors
+                .iter()
+                .copied()
+                .filter(|x| reachable.contains(x))
+                .collect();
+            // Block 0 is also entered from outside the program
+            if predecessors.len() + usize::from(*block == 0) < 2 {
+                continue;
+            }
+            for pred in predecessors {
+                let mut runner = Some(pred);
+                while let Some(current) = runner {
+                    if Some(current) == idom[*block] {
+                        break;
+                    }
+                    frontiers[current].insert(*block);
+                    runner = idom[current];
+                }
+            }
+        }
+        frontiers
+    }
+
+    /// Whether `value` can be read on some path from the start of `from`
+    /// before reaching `until`, where a phi writes it.
+    fn reads_before(&self, from: usize, until: usize, value: usize) -> bool {
+        let mut seen = BTreeSet::from([from]);
+        let mut worklist = vec![from];
+        while let Some(block) = worklist.pop() {
+            if block == until {
+                continue;
+            }
+            if self
+                .cfg
+                .block(block)
+                .range()
+                .any(|x| self.code[x].uses().contains(&value))
+            {
+                return true;
+            }
+            for succ in &self.cfg.block(block).successors {
+                let argument = self.phis[*succ]
+                    .iter()
+                    .flat_map(|x| &x.arguments)
+                    .any(|(edge, x)| *edge == Some(block) && *x == value);
+                if argument {
+                    return true;
+                }
+                if seen.insert(*succ) {
+                    worklist.push(*succ);
+                }
+            }
+        }
+        false
+    }
+
+    /// Translates back out of SSA. Every value is renamed back to its
+    /// original register unless that would clash with another version live at
+    /// the same time, in which case it gets a register past every one the
+    /// program uses, so the result may need `regalloc::allocate` afterwards.
+    /// Phis become register moves on their incoming edges, an `Add` of a
+    /// register nothing writes; moves within one register disappear, so an
+    /// unchanged `Ssa` gives back the program it was built from.
+    pub fn to_program(&self) -> Program {
+        if self.code.is_empty() {
+            return Program::new(vec![]);
+        }
+        let len = self.code.len();
+        let mut names: Vec<usize> = self.values.iter().map(|x| x.register).collect();
+        let highest = names.iter().copied().max().unwrap_or(0);
+
+        let mut moves: BTreeMap<(Option<usize>, usize), Moves> = BTreeMap::new();
+        for (block, phis) in self.phis.iter().enumerate() {
+            for phi in phis {
+                for (edge, argument) in &phi.arguments {
+                    moves
+                        .entry((*edge, block))
+                        .or_default()
+                        .push((*argument, phi.value));
+                }
+            }
+        }
+        let mut sequences = BTreeMap::new();
+        for (edge, pending) in moves {
+            let sequence = sequentialise(pending, &mut names, &self.values);
+            if !sequence.is_empty() {
+                sequences.insert(edge, sequence);
+            }
+        }
+
+        // Moves on a fall-through edge go after the block's branch. Those on
+        // a jump go before it when the other edge doesn't care, and
+        // otherwise into a trampoline after the program that the branch
+        // jumps to instead.
+        let mut before: BTreeMap<usize, Moves> = BTreeMap::new();
+        let mut after: BTreeMap<usize, Moves> = BTreeMap::new();
+        let mut trampolines: Vec<(usize, Moves, Instruction)> = vec![];
+        for ((edge, succ), sequence) in &sequences {
+            let Some(pred) = *edge else {
+                continue;
+            };
+            let block = self.cfg.block(pred);
+            let last = block.end - 1;
+            let fallthrough = (block.end < len).then(|| self.cfg.block_of(block.end));
+            let Instruction::PCSetIfNotZero {
+                register,
+                jump_point,
+            } = self.code[last]
+            else {
+                after.entry(last).or_default().extend(sequence);
+                continue;
+            };
+            if fallthrough == Some(*succ) && self.cfg.block(*succ).start != jump_point {
+                after.entry(last).or_default().extend(sequence);
+                continue;
+            }
+            let written: Vec<usize> = sequence.iter().map(|(_, to)| *to).collect();
+            let safe = fallthrough == Some(*succ)
+                || fallthrough.is_none_or(|x| {
+                    written
+                        .iter()
+                        .all(|value| !self.reads_before(x, *succ, *value))
+                });
+            if safe && !written.contains(&register) {
+                before.entry(last).or_default().extend(sequence);
+                continue;
+            }
+            // The branch register is nonzero on the way in, so re-checking a
+            // copy of it always jumps on
+            names.push(self.values[register].register);
+            let kept = names.len() - 1;
+            let mut trampoline = vec![(register, kept)];
+            trampoline.extend(sequence);
+            trampolines.push((
+                last,
+                trampoline,
+                Instruction::PCSetIfNotZero {
+                    register: kept,
+                    jump_point,
+                },
+            ));
+        }
+
+        names.push(highest + 1);
+        let zero = names.len() - 1;
+        let copy = |(from, to): &(usize, usize)| {
+            InstructionContainer::new(Instruction::Add {
+                rega: *from,
+                regb: zero,
+                outreg: *to,
+            })
+        };
+
+        let program = Program::new(self.code.clone());
+        let entry_moves: Vec<InstructionContainer> = sequences
+            .get(&(None, 0))
+            .map(|x| x.iter().map(copy).collect())
+            .unwrap_or_default();
+        let mut branches = BTreeMap::new();
+        let (mut versions, starts) = program.expand(|idx, instruction| {
+            let mut out = if idx == 0 {
+                entry_moves.clone()
+            } else {
+                vec![]
+            };
+            out.extend(before.get(&idx).into_iter().flatten().map(copy));
+            branches.insert(idx, out.len());
+            out.push(*instruction);
+            out.extend(after.get(&idx).into_iter().flatten().map(copy));
+            out
+        });
+        let mut redirected = BTreeMap::new();
+        if !trampolines.is_empty() {
+            // Falling off the end mustn't run into the trampolines
+            names.push(highest + 1);
+            let exit = names.len() - 1;
+            for instruction in [
+                Instruction::SetReg {
+                    register: exit,
+                    constant: 1,
+                },
+                Instruction::PCSetIfNotZero {
+                    register: exit,
+                    jump_point: usize::MAX,
+                },
+            ] {
+                versions.insert(versions.len(), InstructionContainer::new(instruction));
+            }
+            for (last, trampoline, branch) in &trampolines {
+                redirected.insert(starts[*last] + branches[last], versions.len());
+                for instruction in trampoline.iter().map(copy) {
+                    versions.insert(versions.len(), instruction);
+                }
+                versions.insert(versions.len(), InstructionContainer::new(*branch));
+            }
+        }
+        let end = versions.len();
+        let skipped = entry_moves.len();
+        let versions = versions.retarget(|branch, target| match redirected.get(&branch) {
+            Some(trampoline) => *trampoline,
+            None if target == 0 => skipped,
+            None if target >= len => end,
+            None => starts[target],
+        });
+
+        // Versions of one register share it when they are never live at the
+        // same time. Values that are output go first, so the printed name
+        // survives wherever possible.
+        let cfg = Cfg::new(&versions);
+        let copies: BTreeSet<usize> = versions
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| matches!(x.code(), Instruction::Add { regb, .. } if regb == zero))
+            .map(|(idx, _)| idx)
+            .collect();
+        let graph = InterferenceGraph::with_copies(&versions, &cfg, &copies);
+        let output: BTreeSet<usize> = self
+            .code
+            .iter()
+            .filter_map(|x| match x {
+                Instruction::Output(value) => Some(*value),
+                _ => None,
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..names.len()).collect();
+        order.sort_by_key(|x| !output.contains(x));
+        let mut classes: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut next = highest + 2;
+        let mut assigned = vec![0; names.len()];
+        for version in order {
+            let fits = |register: &usize, classes: &BTreeMap<usize, Vec<usize>>| {
+                classes
+                    .get(register)
+                    .is_none_or(|x| x.iter().all(|y| !graph.interferes(version, *y)))
+            };
+            let register = if fits(&names[version], &classes) {
+                names[version]
+            } else {
+                match (highest + 2..next).find(|x| fits(x, &classes)) {
+                    Some(register) => register,
+                    None => {
+                        next += 1;
+                        next - 1
+                    }
+                }
+            };
+            classes.entry(register).or_default().push(version);
+            assigned[version] = register;
+        }
+
+        versions.rewrite(|idx, instruction| {
+            let renamed = instruction.code().map_registers(|r| assigned[r]);
+            match renamed {
+                Instruction::Add { rega, outreg, .. }
+                    if rega == outreg && copies.contains(&idx) =>
+                {
+                    vec![]
+                }
+                _ => vec![InstructionContainer::new(renamed)],
+            }
+        })
+    }
+}
+
+/// Orders the moves of one edge so they behave as if done all at once: a
+/// move waits until no other one still reads its destination, and cycles are
+/// broken through a temporary.
+fn sequentialise(mut pending: Moves, names: &mut Vec<usize>, values: &[Value]) -> Moves {
+    let mut sequence = vec![];
+    pending.retain(|(from, to)| from != to);
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(_, to)| pending.iter().all(|(from, _)| from != to));
+        match ready {
+            Some(ready) => sequence.push(pending.remove(ready)),
+            None => {
+                let blocked = pending[0].1;
+                names.push(values[blocked].register);
+                let temporary = names.len() - 1;
+                sequence.push((blocked, temporary));
+                for (from, _) in pending.iter_mut() {
+                    if *from == blocked {
+                        *from = temporary;
+                    }
+                }
+            }
+        }
+    }
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+
+    fn output(register: usize, value: i32) -> String {
+        format!("Register: {register} = {value}")
+    }
+
+    // Prints r0 and r1 at the top of each of three iterations, swapping
+    // them through r2 in between, then prints r0 once more
+    fn swapping_loop() -> Program {
+        Program::new(vec![
+            set(3, 0),
+            set(0, 1),
+            set(1, 2),
+            set(4, 3),
+            out(0),
+            out(1),
+            add(0, 3, 2),
+            add(1, 3, 0),
+            add(2, 3, 1),
+            set(5, 1),
+            sub(4, 5, 4),
+            jnz(4, 4),
+            out(0),
+        ])
+    }
+
+    // Propagates the copies of the swap into the header phis' back edge
+    // arguments, so leaving SSA needs a parallel move
+    fn propagate_swap(ssa: &mut Ssa) {
+        let header = ssa.cfg().block_of(4);
+        let latch = Some(header);
+        let phi_of = |ssa: &Ssa, register: usize| {
+            ssa.phis(header)
+                .iter()
+                .position(|x| ssa.value(x.value).register == register)
+                .unwrap()
+        };
+        let (a, b) = (phi_of(ssa, 0), phi_of(ssa, 1));
+        let (a_value, b_value) = (ssa.phis[header][a].value, ssa.phis[header][b].value);
+        for (phi, value) in [(a, b_value), (b, a_value)] {
+            for (edge, argument) in ssa.phis[header][phi].arguments.iter_mut() {
+                if *edge == latch {
+                    *argument = value;
+                }
+            }
+        }
+    }
+
+    // Applies moves one at a time to a state of values
+    fn run_moves(moves: &Moves, state: &mut BTreeMap<usize, usize>) {
+        for (from, to) in moves {
+            let value = state[from];
+            state.insert(*to, value);
+        }
+    }
+
+    #[test]
+    fn unchanged_ssa_round_trips() {
+        let mut programs: Vec<(Program, VirtualMachine)> = examples();
+        programs.push((swapping_loop(), VirtualMachine::new(6)));
+        for (program, mut vm) in programs {
+            let back = Ssa::new(&program).to_program();
+            assert_eq!(back.code(), program.code());
+            assert_same_output_on(&mut vm, &program, &back);
+        }
+    }
+
+    #[test]
+    fn every_value_is_defined_once() {
+        for (program, _) in examples() {
+            let ssa = Ssa::new(&program);
+            let mut defined = BTreeSet::new();
+            for idx in 0..ssa.len() {
+                for value in ssa.instruction(idx).defs() {
+                    assert!(defined.insert(value), "{value} defined twice");
+                    assert_eq!(ssa.value(value).definition, Definition::Instruction(idx));
+                }
+            }
+            for block in 0..ssa.cfg().len() {
+                for phi in ssa.phis(block) {
+                    assert!(defined.insert(phi.value));
+                    assert_eq!(ssa.value(phi.value).definition, Definition::Phi(block));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn phis_go_where_definitions_meet() {
+        let program = Program::new(vec![
+            set(2, 1),
+            jnz(1, 4),
+            set(0, 5),
+            jnz(2, 5),
+            set(0, 7),
+            out(0),
+        ]);
+        let ssa = Ssa::new(&program);
+        let join = ssa.cfg().block_of(5);
+        let phis = ssa.phis(join);
+        assert_eq!(phis.len(), 1);
+        assert_eq!(ssa.value(phis[0].value).register, 0);
+        let mut sources: Vec<Definition> = phis[0]
+            .arguments
+            .iter()
+            .map(|(_, x)| ssa.value(*x).definition)
+            .collect();
+        sources.sort_by_key(|x| format!("{x:?}"));
+        assert_eq!(
+            sources,
+            vec![Definition::Instruction(2), Definition::Instruction(4)]
+        );
+        assert_eq!(ssa.instruction(5), Instruction::Output(phis[0].value));
+        assert_eq!(ssa.users(phis[0].value), vec![User::Instruction(5)]);
+        // Register 2 is only defined once, so it never needs a phi
+        assert!((0..ssa.cfg().len()).all(|x| ssa.phis(x).len() <= 1));
+    }
+
+    #[test]
+    fn loops_get_phis_for_registers_carried_around() {
+        let ssa = Ssa::new(&swapping_loop());
+        let header = ssa.cfg().block_of(4);
+        let mut carried: Vec<usize> = ssa
+            .phis(header)
+            .iter()
+            .map(|x| ssa.value(x.value).register)
+            .collect();
+        carried.sort();
+        // r2 and r5 are written before they're read in the body
+        assert_eq!(carried, vec![0, 1, 4]);
+        for phi in ssa.phis(header) {
+            assert_eq!(phi.arguments.len(), 2);
+        }
+    }
+
+    #[test]
+    fn redefinitions_are_renamed() {
+        let program = Program::new(vec![set(0, 1), out(0), set(0, 2), out(0), out(1)]);
+        let ssa = Ssa::new(&program);
+        let (Instruction::Output(first), Instruction::Output(second)) =
+            (ssa.instruction(1), ssa.instruction(3))
+        else {
+            panic!("outputs changed shape");
+        };
+        assert_ne!(first, second);
+        assert_eq!(ssa.value(first).definition, Definition::Instruction(0));
+        assert_eq!(ssa.value(second).definition, Definition::Instruction(2));
+        // A read with no definition before it sees the initial 0
+        let Instruction::Output(entry) = ssa.instruction(4) else {
+            panic!("output changed shape");
+        };
+        assert_eq!(ssa.value(entry).definition, Definition::Entry);
+    }
+
+    #[test]
+    fn sequentialised_moves_behave_as_if_parallel() {
+        let values: Vec<Value> = (0..4)
+            .map(|register| Value {
+                register,
+                definition: Definition::Entry,
+            })
+            .collect();
+        let cases: Vec<Moves> = vec![
+            // A chain must run back to front
+            vec![(0, 1), (1, 2)],
+            // A swap needs a temporary
+            vec![(0, 1), (1, 0)],
+            // So does a longer cycle
+            vec![(0, 1), (1, 2), (2, 3), (3, 0)],
+            // One source read twice, and a move to itself
+            vec![(0, 1), (0, 2), (3, 3)],
+        ];
+        for pending in cases {
+            let mut names: Vec<usize> = values.iter().map(|x| x.register).collect();
+            let sequence = sequentialise(pending.clone(), &mut names, &values);
+
+            let start: BTreeMap<usize, usize> = (0..names.len()).map(|x| (x, x * 10)).collect();
+            let mut expected = start.clone();
+            for (from, to) in &pending {
+                expected.insert(*to, start[from]);
+            }
+            let mut found = start.clone();
+            run_moves(&sequence, &mut found);
+            for value in 0..values.len() {
+                assert_eq!(found[&value], expected[&value], "{pending:?}: {sequence:?}");
+            }
+            assert!(sequence.iter().all(|(from, to)| from != to));
+        }
+
+        let mut names: Vec<usize> = values.iter().map(|x| x.register).collect();
+        let swap = sequentialise(vec![(0, 1), (1, 0)], &mut names, &values);
+        assert_eq!(swap.len(), 3);
+        assert_eq!(names.len(), values.len() + 1);
+    }
+
+    #[test]
+    fn parallel_moves_on_a_back_edge() {
+        let mut ssa = Ssa::new(&swapping_loop());
+        propagate_swap(&mut ssa);
+        let program = ssa.to_program();
+        let (_, found) = VirtualMachine::new(16).exe(&program).unwrap();
+        let expected: Vec<String> = [(1, 2), (2, 1), (1, 2)]
+            .iter()
+            .flat_map(|(a, b)| [output(0, *a), output(1, *b)])
+            .collect();
+        assert_eq!(found[..6], expected, "{program}");
+        // The value printed after the loop clashes with the phi for r0, so
+        // it is printed under another register
+        assert!(found[6].ends_with("= 2"), "{program}");
+    }
+
+    #[test]
+    fn moves_the_fall_through_reads_go_through_a_trampoline() {
+        let mut ssa = Ssa::new(&swapping_loop());
+        propagate_swap(&mut ssa);
+        // The exit reads r0 from the top of the last iteration, which the
+        // back edge's moves overwrite
+        let header = ssa.cfg().block_of(4);
+        let a = ssa
+            .phis(header)
+            .iter()
+            .find(|x| ssa.value(x.value).register == 0)
+            .unwrap()
+            .value;
+        ssa.replace(12, Instruction::Output(a));
+        let program = ssa.to_program();
+
+        // The trampoline sits past the end, behind a jump over it
+        let code = program.code();
+        let exit = code
+            .iter()
+            .position(|x| matches!(x, Instruction::PCSetIfNotZero { jump_point, .. } if *jump_point == code.len()))
+            .expect("no jump over the trampolines");
+        assert!(code[exit + 1..]
+            .iter()
+            .any(|x| matches!(x, Instruction::PCSetIfNotZero { jump_point: 4, .. })));
+
+        let (_, found) = VirtualMachine::new(16).exe(&program).unwrap();
+        let expected: Vec<String> = [(1, 2), (2, 1), (1, 2)]
+            .iter()
+            .flat_map(|(a, b)| [output(0, *a), output(1, *b)])
+            .chain([output(0, 1)])
+            .collect();
+        assert_eq!(found, expected, "{program}");
+    }
+}
@@ -0,0 +1,423 @@
+use crate::cfg::Cfg;
+use crate::dataflow::register_liveness;
+use crate::passes::Pass;
+use crate::rules::verify::Verifier;
+use crate::rules::{compose, decompose, parse_rules, Condition, Kind, Operand, Rule, RuleError};
+use crate::rules::{Opcode, Template};
+use crate::Instruction;
+use crate::InstructionContainer;
+use crate::Program;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "# Cache of the superoptimiser, one rule per window it has searched. A rule\n\
+                      # that rewrites a window into itself records that nothing cheaper exists.\n";
+
+/// A window with its registers and variables numbered by first appearance,
+/// so windows that only differ in naming share a search.
+struct Canonical {
+    code: Vec<Instruction>,
+    registers: Vec<usize>,
+    variables: Vec<usize>,
+}
+
+impl Canonical {
+    fn new(window: &[Instruction]) -> Self {
+        let mut registers = vec![];
+        let mut variables = vec![];
+        let code = window
+            .iter()
+            .map(|instruction| {
+                map_operands(instruction, |kind, value| {
+                    let names = match kind {
+                        Kind::Register => &mut registers,
+                        Kind::Variable => &mut variables,
+                        _ => return value,
+                    };
+                    let value = value as usize;
+                    let idx = names.iter().position(|x| *x == value).unwrap_or_else(|| {
+                        names.push(value);
+                        names.len() - 1
+                    });
+                    idx as i64
+                })
+            })
+            .collect();
+        Self {
+            code,
+            registers,
+            variables,
+        }
+    }
+
+    /// Names `code`, written over the canonical numbering, back into the
+    /// window's own registers and variables.
+    fn restore(&self, code: &[Instruction]) -> Vec<Instruction> {
+        code.iter()
+            .map(|instruction| {
+                map_operands(instruction, |kind, value| match kind {
+                    Kind::Register => self.registers[value as usize] as i64,
+                    Kind::Variable => self.variables[value as usize] as i64,
+                    _ => value,
+                })
+            })
+            .collect()
+    }
+}
+
+fn map_operands(instruction: &Instruction, mut f: impl FnMut(Kind, i64) -> i64) -> Instruction {
+    let (opcode, values) = decompose(instruction);
+    let values: Vec<i64> = opcode
+        .kinds()
+        .iter()
+        .zip(values)
+        .map(|(kind, value)| f(*kind, value))
+        .collect();
+    compose(opcode, &values).expect("Renaming keeps operands in range")
+}
+
+fn literal(instruction: &Instruction) -> Template {
+    let (opcode, values) = decompose(instruction);
+    Template {
+        opcode,
+        operands: values.into_iter().map(Operand::Literal).collect(),
+    }
+}
+
+/// What a cache entry is looked up by: the canonical window and which of its
+/// registers are dead afterwards.
+fn key(rule: &Rule) -> String {
+    let mut key: Vec<String> = rule.pattern.iter().map(|x| x.to_string()).collect();
+    key.extend(rule.conditions.iter().map(|x| x.to_string()));
+    key.join("; ")
+}
+
+/// Replaces short runs of straight-line code with the cheapest equivalent
+/// sequence it can find by trying every one up to `max_length` instructions
+/// long. Candidates are built from `Add`, `Sub`, `SetReg`, `Var`, `Load` and
+/// `Store` over the window's own registers, variables and constants (plus 0
+/// and 1), cheapest first. Each is screened on a few random states and then
+/// checked with `rules::verify`, where registers the rest of the program
+/// never reads are free to differ. Windows stop at branches and `Output`s.
+///
+/// Results are cached by canonical window, and the cache can be kept on disk
+/// as a rule file between runs.
+pub struct Superoptimizer {
+    max_length: usize,
+    max_window: usize,
+    max_registers: usize,
+    cache: RefCell<BTreeMap<String, Rule>>,
+    path: Option<PathBuf>,
+    screen: Verifier,
+    verifier: Verifier,
+}
+
+impl Default for Superoptimizer {
+    fn default() -> Self {
+        Self {
+            max_length: 2,
+            max_window: 3,
+            max_registers: 3,
+            cache: RefCell::new(BTreeMap::new()),
+            path: None,
+            screen: Verifier::new().with_trials(4),
+            verifier: Verifier::new().with_trials(64),
+        }
+    }
+}
+
+impl Superoptimizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the cache in the rule file at `path`, reading it if it exists.
+    /// Cached rewrites that fail verification, say because an older verifier
+    /// let them through, are dropped and searched again.
+    pub fn with_cache(mut self, path: impl AsRef<Path>) -> Result<Self, RuleError> {
+        let path = path.as_ref().to_path_buf();
+        let rules = match std::fs::read_to_string(&path) {
+            Ok(text) => parse_rules(&text)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(RuleError::Io(error)),
+        };
+        let rules = rules
+            .into_iter()
+            .filter(|x| x.pattern == x.replacement || self.verifier.check(x).is_ok_and(|x| x > 0));
+        self.cache = RefCell::new(rules.map(|x| (key(&x), x)).collect());
+        self.path = Some(path);
+        Ok(self)
+    }
+
+    /// Longest sequence tried as a replacement.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Longest window replaced.
+    pub fn with_max_window(mut self, max_window: usize) -> Self {
+        self.max_window = max_window;
+        self
+    }
+
+    /// Windows using more registers than this are skipped.
+    pub fn with_max_registers(mut self, max_registers: usize) -> Self {
+        self.max_registers = max_registers;
+        self
+    }
+
+    pub fn cache_len(&self) -> usize {
+        self.cache.borrow().len()
+    }
+
+    /// Writes the cache back to the file it was read from, if any.
+    pub fn save(&self) -> Result<(), RuleError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rules: Vec<String> = self
+            .cache
+            .borrow()
+            .values()
+            .map(|x| x.to_string())
+            .collect();
+        let text = format!("{HEADER}\n{}", rules.join("\n"));
+        std::fs::write(path, text).map_err(RuleError::Io)
+    }
+
+    /// The cheapest equivalent of `window` when only the registers in
+    /// `live_out` are read after it, `None` if it's already the cheapest.
+    pub fn optimise_window(
+        &self,
+        window: &[Instruction],
+        live_out: &BTreeSet<usize>,
+    ) -> Option<Vec<Instruction>> {
+        let canonical = Canonical::new(window);
+        if canonical.registers.len() > self.max_registers {
+            return None;
+        }
+        let dead = canonical
+            .registers
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !live_out.contains(x))
+            .map(|(idx, _)| Condition::Dead(Operand::Literal(idx as i64)));
+        let mut rule = Rule {
+            name: String::new(),
+            pattern: canonical.code.iter().map(literal).collect(),
+            conditions: dead.collect(),
+            replacement: vec![],
+        };
+        let key = key(&rule);
+
+        let cached = self.cache.borrow().get(&key).cloned();
+        let rule = match cached {
+            Some(rule) => rule,
+            None => {
+                let best = self
+                    .search(&canonical, &rule)
+                    .unwrap_or_else(|| canonical.code.clone());
+                rule.name = format!("superopt-{}", self.cache.borrow().len() + 1);
+                rule.replacement = best.iter().map(literal).collect();
+                self.cache.borrow_mut().insert(key, rule.clone());
+                rule
+            }
+        };
+        let best = rule.instantiate(&BTreeMap::new())?;
+        (best != canonical.code).then(|| canonical.restore(&best))
+    }
+
+    /// The first candidate cheaper than the window that passes verification.
+    fn search(&self, canonical: &Canonical, rule: &Rule) -> Option<Vec<Instruction>> {
+        let cost = Program::new(canonical.code.clone()).cost();
+        let alphabet = self.alphabet(canonical);
+        let mut candidates: Vec<Vec<Instruction>> = vec![vec![]];
+        let mut frontier: Vec<Vec<Instruction>> = vec![vec![]];
+        for _ in 0..self.max_length {
+            frontier = frontier
+                .iter()
+                .flat_map(|prefix| {
+                    alphabet.iter().map(move |x| {
+                        let mut candidate = prefix.clone();
+                        candidate.push(*x);
+                        candidate
+                    })
+                })
+                .filter(|x| Program::new(x.clone()).cost() < cost)
+                .collect();
+            candidates.extend(frontier.iter().cloned());
+        }
+        candidates.sort_by_key(|x| Program::new(x.clone()).cost());
+
+        let mut rule = rule.clone();
+        candidates.into_iter().find(|candidate| {
+            rule.replacement = candidate.iter().map(literal).collect();
+            let passes = |verifier: &Verifier| verifier.check(&rule).is_ok_and(|x| x > 0);
+            passes(&self.screen) && passes(&self.verifier)
+        })
+    }
+
+    /// Every instruction a replacement may use.
+    fn alphabet(&self, canonical: &Canonical) -> Vec<Instruction> {
+        let registers = canonical.registers.len() as i64;
+        let variables = canonical.variables.len() as i64;
+        let mut constants = BTreeSet::from([0, 1]);
+        for instruction in &canonical.code {
+            if let Instruction::SetReg { constant, .. } = instruction {
+                constants.insert(i64::from(*constant));
+            }
+        }
+
+        let mut alphabet = vec![];
+        for opcode in [
+            Opcode::Add,
+            Opcode::Sub,
+            Opcode::SetReg,
+            Opcode::Var,
+            Opcode::Load,
+            Opcode::Store,
+        ] {
+            let mut operands: Vec<Vec<i64>> = vec![vec![]];
+            for kind in opcode.kinds() {
+                let values: Vec<i64> = match kind {
+                    Kind::Register => (0..registers).collect(),
+                    Kind::Variable => (0..variables).collect(),
+                    Kind::Constant => constants.iter().copied().collect(),
+                    Kind::Jump => vec![],
+                };
+                operands = operands
+                    .iter()
+                    .flat_map(|prefix| {
+                        values.iter().map(move |x| {
+                            let mut operands = prefix.clone();
+                            operands.push(*x);
+                            operands
+                        })
+                    })
+                    .collect();
+            }
+            alphabet.extend(operands.iter().filter_map(|x| compose(opcode, x)));
+        }
+        alphabet
+    }
+}
+
+impl Pass for Superoptimizer {
+    fn name(&self) -> &'static str {
+        "superopt"
+    }
+
+    fn run(&self, program: &Program) -> Program {
+        let cfg = Cfg::new(program);
+        let live = register_liveness(program, &cfg);
+        let code = program.code();
+
+        let mut replaced: BTreeMap<usize, Vec<InstructionContainer>> = BTreeMap::new();
+        let mut removed = BTreeSet::new();
+        for block in cfg.blocks() {
+            let mut idx = block.start;
+            while idx < block.end {
+                let straight = (idx..block.end)
+                    .take_while(|x| {
+                        !matches!(
+                            code[*x],
+                            Instruction::PCSetIfNotZero { .. } | Instruction::Output(_)
+                        )
+                    })
+                    .count();
+                // Longest window first
+                let found = (1..=straight.min(self.max_window)).rev().find_map(|len| {
+                    let end = idx + len;
+                    let best = self.optimise_window(&code[idx..end], live.after(end - 1))?;
+                    Some((end, best))
+                });
+                match found {
+                    Some((end, best)) => {
+                        replaced.insert(
+                            idx,
+                            best.into_iter().map(InstructionContainer::new).collect(),
+                        );
+                        removed.extend(idx + 1..end);
+                        idx = end;
+                    }
+                    None => idx += 1,
+                }
+            }
+        }
+
+        program.rewrite(|idx, instruction| {
+            if let Some(replacement) = replaced.get(&idx) {
+                replacement.clone()
+            } else if removed.contains(&idx) {
+                vec![]
+            } else {
+                vec![*instruction]
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn declarations_are_kept() {
+        let program = Program::new(vec![set(0, 5), var(0), store(0, 0), load(1, 0), out(1)]);
+        let mut vm = VirtualMachine::new(4);
+        assert_eq!(
+            vm.exe(&program).unwrap(),
+            (7, vec!["Register: 1 = 5".to_string()])
+        );
+
+        let superoptimizer = Superoptimizer::new();
+        let optimised = superoptimizer.run(&program);
+        assert!(optimised.code().contains(&var(0)), "{optimised}");
+        assert_same_output_on(&mut vm, &program, &optimised);
+        // Nor does the cache keep a rule that drops one
+        for rule in superoptimizer.cache.borrow().values() {
+            let declares = |x: &[Template]| x.iter().any(|x| x.opcode == Opcode::Var);
+            assert!(
+                !declares(&rule.pattern) || declares(&rule.replacement),
+                "{rule}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_cached_rules_are_dropped() {
+        let path = std::env::temp_dir().join(format!("superopt-{}.rules", std::process::id()));
+        std::fs::write(
+            &path,
+            "rule superopt-1
+                SetReg { register: 0, constant: 5 }
+                Var(0)
+                Store { register: 0, variable: 0 }
+            =>
+                SetReg { register: 0, constant: 5 }
+                Store { register: 0, variable: 0 }
+
+            rule superopt-2
+                SetReg { register: 0, constant: 1 }
+            =>
+                SetReg { register: 0, constant: 1 }",
+        )
+        .unwrap();
+        let superoptimizer = Superoptimizer::new().with_cache(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(superoptimizer.cache_len(), 1);
+    }
+
+    #[test]
+    fn cheaper_windows_are_replaced() {
+        // The first write is dead and the sum is a constant
+        let program = Program::new(vec![set(0, 1), set(0, 2), set(1, 3), add(0, 1, 2), out(2)]);
+        let optimised = Superoptimizer::new().run(&program);
+        let (before, after) = assert_same_output(&program, &optimised, 4);
+        assert!(after < before, "{optimised}");
+    }
+}
@@ -0,0 +1,77 @@
+//! Shorthands for writing programs in unit tests.
+
+use crate::programs::{add_two, count_to_x, vecmul};
+use crate::Instruction;
+use crate::Program;
+use crate::VirtualMachine;
+use std::collections::HashMap;
+
+pub fn set(register: usize, constant: i32) -> Instruction {
+    Instruction::SetReg { register, constant }
+}
+
+pub fn add(rega: usize, regb: usize, outreg: usize) -> Instruction {
+    Instruction::Add { rega, regb, outreg }
+}
+
+pub fn sub(rega: usize, regb: usize, outreg: usize) -> Instruction {
+    Instruction::Sub { rega, regb, outreg }
+}
+
+pub fn var(variable: usize) -> Instruction {
+    Instruction::Var(variable)
+}
+
+pub fn load(register: usize, variable: usize) -> Instruction {
+    Instruction::Load { register, variable }
+}
+
+pub fn store(register: usize, variable: usize) -> Instruction {
+    Instruction::Store { register, variable }
+}
+
+pub fn jnz(register: usize, jump_point: usize) -> Instruction {
+    Instruction::PCSetIfNotZero {
+        register,
+        jump_point,
+    }
+}
+
+pub fn out(register: usize) -> Instruction {
+    Instruction::Output(register)
+}
+
+/// The example programs with a VM they run on. `vecmul` reads its input
+/// vectors from the VM's base memory.
+pub fn examples() -> Vec<(Program, VirtualMachine)> {
+    let vectors: HashMap<usize, i32> = (0..5)
+        .flat_map(|x| [(x, x as i32 + 1), (x + 10, x as i32 + 1)])
+        .collect();
+    vec![
+        (add_two::prog(), VirtualMachine::new(4)),
+        (count_to_x::prog(20), VirtualMachine::new(4)),
+        (
+            vecmul::prog(),
+            VirtualMachine::from_memory_state(4, vectors),
+        ),
+    ]
+}
+
+/// Runs both programs on a VM with `registers` registers, asserting they
+/// print the same, and returns their costs.
+pub fn assert_same_output(before: &Program, after: &Program, registers: usize) -> (usize, usize) {
+    assert_same_output_on(&mut VirtualMachine::new(registers), before, after)
+}
+
+pub fn assert_same_output_on(
+    vm: &mut VirtualMachine,
+    before: &Program,
+    after: &Program,
+) -> (usize, usize) {
+    let (before_cost, expected) = vm.exe(before).expect("original program failed");
+    let (after_cost, found) = vm
+        .exe(after)
+        .unwrap_or_else(|x| panic!("transformed program failed with {x:?}:\n{after}"));
+    assert_eq!(found, expected, "output changed:\n{after}");
+    (before_cost, after_cost)
+}
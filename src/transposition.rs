@@ -0,0 +1,192 @@
+use crate::op_finder::ProgramState;
+use crate::optimizer::CacheStatistics;
+use crate::Instruction;
+use crate::Program;
+use crate::VirtualMachine;
+use std::collections::HashMap;
+
+/// Statistics of a search node, shared by every node that reaches the same
+/// program.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NodeStatistics {
+    pub visits: u32,
+    /// Sum of the rewards backpropagated through the node.
+    pub total: f64,
+    pub best: f64,
+}
+
+impl NodeStatistics {
+    pub fn update(&mut self, reward: f64) {
+        self.visits += 1;
+        self.total += reward;
+        self.best = self.best.max(reward);
+    }
+}
+
+/// Results of executing a program, cached by content for one search. Since
+/// the reference behaviour is fixed for the search, the reward is cached
+/// with the execution.
+#[derive(Debug, Clone)]
+struct Outcome {
+    out: Option<(usize, Vec<String>)>,
+    reward: isize,
+    /// VM steps the execution took.
+    steps: usize,
+}
+
+/// Caches execution outcomes and rewards by the program's instructions,
+/// ignoring the ids of their containers, and holds the statistics of search
+/// nodes so nodes that reach the same program along different action orders
+/// are merged. Keying on the instructions themselves means two different
+/// programs can never share an entry.
+#[derive(Debug, Default)]
+pub struct TranspositionTable {
+    outcomes: HashMap<Vec<Instruction>, Outcome>,
+    nodes: HashMap<Vec<Instruction>, NodeStatistics>,
+    hits: usize,
+    misses: usize,
+    /// Steps the hits would have taken on the VM.
+    saved_steps: usize,
+}
+
+impl TranspositionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `program` unless an identical one already ran, returning its
+    /// cost and output, `None` if it fails.
+    pub fn execute(
+        &mut self,
+        vm: &mut VirtualMachine,
+        program: &Program,
+        real: &(usize, Vec<String>),
+    ) -> Option<&(usize, Vec<String>)> {
+        self.outcome(vm, program, real).out.as_ref()
+    }
+
+    /// Reward of `program` as `ProgramState::reward` gives it, `None` if it
+    /// fails to run.
+    pub fn reward(
+        &mut self,
+        vm: &mut VirtualMachine,
+        program: &Program,
+        real: &(usize, Vec<String>),
+    ) -> Option<isize> {
+        let outcome = self.outcome(vm, program, real);
+        outcome.out.as_ref().map(|_| outcome.reward)
+    }
+
+    fn outcome(
+        &mut self,
+        vm: &mut VirtualMachine,
+        program: &Program,
+        real: &(usize, Vec<String>),
+    ) -> &Outcome {
+        let key = program.code();
+        if let Some(outcome) = self.outcomes.get(&key) {
+            self.hits += 1;
+            self.saved_steps += outcome.steps;
+        } else {
+            self.misses += 1;
+            let mut state = ProgramState::new(program.clone());
+            let steps = vm.steps();
+            let outcome = match state.exe(vm) {
+                Ok(()) => Outcome {
+                    reward: state.reward(real),
+                    out: state.output().cloned(),
+                    steps: vm.steps() - steps,
+                },
+                Err(_) => Outcome {
+                    out: None,
+                    reward: -100,
+                    steps: vm.steps() - steps,
+                },
+            };
+            self.outcomes.insert(key.clone(), outcome);
+        }
+        &self.outcomes[&key]
+    }
+
+    /// Executions and steps the cache answered instead of the VM.
+    pub fn saved(&self) -> (usize, usize) {
+        (self.hits, self.saved_steps)
+    }
+
+    /// Statistics of the nodes reaching the program with these instructions.
+    pub fn node(&self, program: &[Instruction]) -> NodeStatistics {
+        self.nodes.get(program).copied().unwrap_or_default()
+    }
+
+    pub fn update(&mut self, program: &[Instruction], reward: f64) {
+        match self.nodes.get_mut(program) {
+            Some(node) => node.update(reward),
+            None => {
+                let mut node = NodeStatistics::default();
+                node.update(reward);
+                self.nodes.insert(program.to_vec(), node);
+            }
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics {
+            hits: self.hits,
+            misses: self.misses,
+            programs: self.nodes.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn identical_programs_share_an_entry() {
+        let program = Program::new(vec![set(0, 2), out(0)]);
+        // Same instructions in new containers
+        let copy = Program::new(program.code());
+        let mut vm = VirtualMachine::new(4);
+        let real = vm.exe(&program).unwrap();
+        let mut table = TranspositionTable::new();
+
+        assert_eq!(table.execute(&mut vm, &program, &real), Some(&real));
+        assert_eq!(table.execute(&mut vm, &copy, &real), Some(&real));
+        assert_eq!(table.saved().0, 1);
+
+        table.update(&program.code(), 0.5);
+        table.update(&copy.code(), 1.0);
+        let node = table.node(&program.code());
+        assert_eq!(node.visits, 2);
+        assert_eq!(node.best, 1.0);
+        assert_eq!(table.statistics().programs, 1);
+    }
+
+    #[test]
+    fn different_programs_never_share_an_entry() {
+        let programs = [
+            Program::new(vec![set(0, 2), out(0)]),
+            Program::new(vec![set(0, 3), out(0)]),
+            Program::new(vec![set(1, 2), out(1)]),
+        ];
+        let mut vm = VirtualMachine::new(4);
+        let real = vm.exe(&programs[0]).unwrap();
+        let mut table = TranspositionTable::new();
+        for program in &programs {
+            let expected = vm.exe(program).unwrap();
+            assert_eq!(table.execute(&mut vm, program, &real), Some(&expected));
+            table.update(&program.code(), 1.0);
+        }
+        assert_eq!(table.saved().0, 0);
+        assert_eq!(table.statistics().misses, 3);
+        for program in &programs {
+            assert_eq!(table.node(&program.code()).visits, 1);
+        }
+        // A failing program caches the failure
+        let failing = Program::new(vec![load(0, 0), out(0)]);
+        assert_eq!(table.execute(&mut vm, &failing, &real), None);
+        assert_eq!(table.reward(&mut vm, &failing, &real), None);
+    }
+}
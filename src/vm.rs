@@ -4,9 +4,12 @@ use std::collections::HashMap;
 
 const TIMEOUT: usize = 10000;
 
+#[derive(Clone)]
 pub struct VirtualMachine {
     register_count: usize,
     base_memory: HashMap<usize, i32>,
+    executions: usize,
+    steps: usize,
 }
 
 #[derive(Debug)]
@@ -21,6 +24,8 @@ impl VirtualMachine {
         Self {
             register_count,
             base_memory: HashMap::new(),
+            executions: 0,
+            steps: 0,
         }
     }
 
@@ -28,29 +33,53 @@ impl VirtualMachine {
         Self {
             register_count,
             base_memory,
+            executions: 0,
+            steps: 0,
         }
     }
 
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    /// Programs run so far.
+    pub fn executions(&self) -> usize {
+        self.executions
+    }
+
+    /// Instructions executed so far, over every run.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
     pub fn exe(&mut self, instructions: &Program) -> Result<(usize, Vec<String>), ExecutionError> {
+        let mut its = 0;
+        let result = self.run(instructions, &mut its);
+        self.executions += 1;
+        self.steps += its;
+        result
+    }
+
+    fn run(
+        &self,
+        instructions: &Program,
+        its: &mut usize,
+    ) -> Result<(usize, Vec<String>), ExecutionError> {
         let mut pc = 0;
         let mut cost = 0;
         let mut output = vec![];
-        let mut its = 0;
 
         let mut registers = vec![0; self.register_count];
         let mut memory = self.base_memory.clone();
         let mut instruction_counter = HashMap::new();
 
-        loop {
-            let Some(instruction) = instructions.get(pc) else {
-                break;
-            };
-            if its > TIMEOUT {
+        while let Some(instruction) = instructions.get(pc) {
+            if *its > TIMEOUT {
                 return Err(ExecutionError::Timeout);
             }
 
             pc += 1;
-            its += 1;
+            *its += 1;
             cost += instruction.cost();
 
             instruction_counter.insert(
@@ -0,0 +1,33 @@
+use m_prime::op_finder::{Action, ProgramState};
+use m_prime::{Instruction, Program};
+
+#[test]
+fn replace_swaps_out_the_instruction() {
+    let program = Program::new(vec![
+        Instruction::SetReg {
+            register: 0,
+            constant: 1,
+        },
+        Instruction::SetReg {
+            register: 1,
+            constant: 2,
+        },
+        Instruction::Output(1),
+    ]);
+    let replacement = Instruction::SetReg {
+        register: 1,
+        constant: 3,
+    };
+    let state = ProgramState::new(program).applying(&vec![Action::Replace(1, replacement)]);
+    assert_eq!(
+        state.program().code(),
+        vec![
+            Instruction::SetReg {
+                register: 0,
+                constant: 1,
+            },
+            replacement,
+            Instruction::Output(1),
+        ]
+    );
+}
@@ -0,0 +1,269 @@
+use m_prime::observer::{NoopReporter, SearchEvent};
+use m_prime::op_finder::{
+    mcts, mcts_with_budget, ActionKind, Mcts, MctsConfig, ProgramState, Value,
+};
+use m_prime::optimizer::{Budget, Cancellation, Optimizer};
+use m_prime::programs::add_two;
+use m_prime::VirtualMachine;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Cheapest `add_two` reachable by removing instructions: `SetReg r1 1`, two
+/// `Add`s into r0 (which starts at 0) and the `Output`.
+const BEST_COST: usize = 4;
+
+fn search(config: MctsConfig, epochs: usize) -> usize {
+    let mcts = Mcts::new(config);
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let optimized = mcts.optimize(&program, &mut vm, &reference, Budget::iterations(epochs));
+    let (cost, output) = vm.exe(&optimized.program).unwrap();
+    assert_eq!(output, reference.1);
+    assert_eq!(cost, optimized.statistics.best_cost);
+    cost
+}
+
+fn removals() -> MctsConfig {
+    MctsConfig {
+        rollout_depth: 16,
+        actions: vec![ActionKind::Remove],
+        ..MctsConfig::default()
+    }
+}
+
+#[test]
+fn converges_on_add_two_with_mean_value() {
+    assert_eq!(search(removals(), 2_000), BEST_COST);
+}
+
+#[test]
+fn converges_on_add_two_with_max_value() {
+    let config = MctsConfig {
+        value: Value::Max,
+        ..removals()
+    };
+    assert_eq!(search(config, 2_000), BEST_COST);
+}
+
+#[test]
+fn converges_for_any_exploration_constant() {
+    for exploration in [0.1, 1.0, 4.0] {
+        let config = MctsConfig {
+            exploration,
+            ..removals()
+        };
+        assert_eq!(search(config, 2_000), BEST_COST);
+    }
+}
+
+#[test]
+fn converges_on_add_two_with_the_default_actions() {
+    assert_eq!(search(MctsConfig::default(), 2_000), BEST_COST);
+}
+
+#[test]
+fn same_config_gives_same_result() {
+    let config = MctsConfig {
+        epochs: 50,
+        rollout_depth: 3,
+        rollouts: 2,
+        actions: vec![ActionKind::Remove, ActionKind::Replace],
+        seed: 7,
+        ..MctsConfig::default()
+    };
+    let program = add_two::prog();
+    let run = || {
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&program).unwrap();
+        mcts(program.clone(), &mut vm, &reference, &config)
+            .map(|(saving, found)| (saving, found.map(|x| x.code())))
+    };
+    let first = run();
+    assert!(first.is_some());
+    for _ in 0..5 {
+        assert_eq!(run(), first);
+    }
+}
+
+#[test]
+fn reuses_executions_of_programs_reached_twice() {
+    // Removing two instructions in either order reaches the same program
+    let config = MctsConfig {
+        epochs: 500,
+        ..removals()
+    };
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let budget = Budget::iterations(config.epochs);
+    let cache =
+        mcts_with_budget(program, &mut vm, &reference, &config, budget, &NoopReporter).cache;
+    assert!(cache.hits > 0);
+    assert!(cache.programs < cache.hits + cache.misses);
+}
+
+#[test]
+fn parallel_trees_converge_and_agree() {
+    let config = MctsConfig {
+        threads: 4,
+        ..removals()
+    };
+    assert_eq!(search(config.clone(), 500), BEST_COST);
+
+    let program = add_two::prog();
+    let run = || {
+        let mut vm = VirtualMachine::new(4);
+        let reference = vm.exe(&program).unwrap();
+        let config = MctsConfig {
+            epochs: 50,
+            ..config.clone()
+        };
+        mcts(program.clone(), &mut vm, &reference, &config)
+            .map(|(saving, found)| (saving, found.map(|x| x.code())))
+    };
+    let first = run();
+    for _ in 0..3 {
+        assert_eq!(run(), first);
+    }
+}
+
+#[test]
+fn stops_when_the_budget_is_spent() {
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let config = removals();
+
+    let start = Instant::now();
+    let budget = Budget::time(Duration::from_millis(200));
+    let run = mcts_with_budget(
+        program.clone(),
+        &mut vm,
+        &reference,
+        &config,
+        budget,
+        &NoopReporter,
+    );
+    assert!(run.epochs > 0);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let executions = vm.executions();
+    let budget = Budget::executions(50);
+    mcts_with_budget(
+        program.clone(),
+        &mut vm,
+        &reference,
+        &config,
+        budget,
+        &NoopReporter,
+    );
+    // Runs the cache answers count too, so the VM may run fewer
+    assert!(vm.executions() - executions <= 51);
+
+    let steps = vm.steps();
+    let budget = Budget::steps(1_000);
+    mcts_with_budget(program, &mut vm, &reference, &config, budget, &NoopReporter);
+    assert!(vm.steps() - steps < 1_000 + 10_001);
+}
+
+#[test]
+fn cancelling_stops_the_search() {
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let config = MctsConfig {
+        threads: 2,
+        ..removals()
+    };
+    let cancellation = Cancellation::new();
+    let budget = Budget::iterations(usize::MAX).with_cancellation(cancellation.clone());
+    let stopper = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        cancellation.cancel();
+    });
+    let optimized = Mcts::new(config).optimize(&program, &mut vm, &reference, budget);
+    stopper.join().unwrap();
+    assert!(optimized.statistics.iterations > 0);
+    assert_eq!(vm.exe(&optimized.program).unwrap().1, reference.1);
+}
+
+#[test]
+fn observer_sees_every_epoch_and_how_to_reach_each_best() {
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let config = MctsConfig {
+        snapshot_interval: 10,
+        ..removals()
+    };
+    let (epochs, snapshots, bests) = (Mutex::new(0), Mutex::new(0), Mutex::new(vec![]));
+    let observer = |event: &SearchEvent| match event {
+        SearchEvent::EpochFinished { .. } => *epochs.lock().unwrap() += 1,
+        SearchEvent::Snapshot { .. } => *snapshots.lock().unwrap() += 1,
+        SearchEvent::NewBest {
+            program, actions, ..
+        } => bests
+            .lock()
+            .unwrap()
+            .push((program.code(), actions.to_vec())),
+    };
+    let budget = Budget::iterations(200);
+    let run = mcts_with_budget(
+        program.clone(),
+        &mut vm,
+        &reference,
+        &config,
+        budget,
+        &observer,
+    );
+
+    assert_eq!(*epochs.lock().unwrap(), run.epochs);
+    assert_eq!(*snapshots.lock().unwrap(), run.epochs / 10);
+    let bests = bests.into_inner().unwrap();
+    assert!(!bests.is_empty());
+    for (code, actions) in bests {
+        let replayed = ProgramState::new(program.clone()).applying(&actions);
+        assert_eq!(replayed.program().code(), code);
+    }
+}
+
+#[test]
+fn never_returns_a_program_with_other_output() {
+    let mcts = Mcts::new(removals()).with_observer(NoopReporter);
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let (cost, _) = vm.exe(&program).unwrap();
+    // Nothing prints this, so nothing found may replace the input
+    let reference = (cost, vec!["Register: 0 = 3".to_string()]);
+    let optimized = mcts.optimize(&program, &mut vm, &reference, Budget::iterations(200));
+    assert_eq!(optimized.program.code(), program.code());
+    assert_eq!(optimized.statistics.best_cost, cost);
+}
+
+#[test]
+fn parallel_trees_split_the_epochs() {
+    let program = add_two::prog();
+    let mut vm = VirtualMachine::new(4);
+    let reference = vm.exe(&program).unwrap();
+    let config = MctsConfig {
+        threads: 4,
+        ..removals()
+    };
+    let per_tree = Mutex::new([0; 4]);
+    let observer = |event: &SearchEvent| {
+        if let SearchEvent::EpochFinished { tree, .. } = event {
+            per_tree.lock().unwrap()[*tree] += 1;
+        }
+    };
+    let run = mcts_with_budget(
+        program,
+        &mut vm,
+        &reference,
+        &config,
+        Budget::iterations(102),
+        &observer,
+    );
+    assert_eq!(run.epochs, 102);
+    assert_eq!(*per_tree.lock().unwrap(), [26, 26, 25, 25]);
+}